@@ -1,18 +1,25 @@
-use std::io::stdout;
+use std::io::{Error, ErrorKind, stdout};
 
-use argparse::{ArgumentParser, Collect, StoreTrue};
+use argparse::{ArgumentParser, Collect, StoreOption, StoreTrue};
 
 extern crate kirby;
 
+use kirby::dead_letter::DeadLetter;
+use kirby::filter::Filter;
+
 struct Options {
     paths: Vec<String>,
     gzip: bool,
+    dead_letter: Option<String>,
+    filter: Option<String>,
 }
 
 fn main() -> Result<(), std::io::Error> {
     let mut opts = Options {
         paths: ["test/sample_500.log".to_string()].to_vec(),
         gzip: false,
+        dead_letter: None,
+        filter: None,
     };
 
     {
@@ -20,19 +27,40 @@ fn main() -> Result<(), std::io::Error> {
         ap.set_description("Parse a RubyGems.org Fastly JSON log file.");
         ap.refer(&mut opts.gzip)
             .add_option(&["--gzip"], StoreTrue, "Gzip output");
+        ap.refer(&mut opts.dead_letter).add_option(
+            &["--dead-letter"],
+            StoreOption,
+            "Write records that can't be converted to this file, as JSON, and keep going",
+        );
+        ap.refer(&mut opts.filter).add_option(
+            &["--filter"],
+            StoreOption,
+            "Write only rows that match an expression, like 'gem == \"rails\" && ua.ci != null'",
+        );
         ap.refer(&mut opts.paths).add_argument(
             "FILE",
             Collect,
-            "Paths to the log file(s) to process",
+            "Log files, directories, or glob patterns to process, or - for stdin",
         );
         ap.parse_args_or_exit();
     }
 
-    let context = kirby::clickhouse::Context::new(&kirby::full_name_lengths::FULL_NAMES);
+    let mut context = kirby::clickhouse::Context::new(&kirby::full_name_lengths::FULL_NAMES);
+    if let Some(path) = &opts.dead_letter {
+        context = context.with_dead_letter(DeadLetter::create(path)?);
+    }
+    if let Some(expr) = &opts.filter {
+        let filter = Filter::for_clickhouse(expr)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid filter: {}", e)))?;
+        context = context.with_filter(filter);
+    }
 
-    for path in opts.paths {
+    for path in kirby::file::expand_paths(&opts.paths)? {
         kirby::file_clickhouse(&mut stdout(), &path, &context)?
     }
+    if let Some(dead_letter) = &context.dead_letter {
+        dead_letter.flush()?;
+    }
     Ok(())
 }
 #[cfg(test)]
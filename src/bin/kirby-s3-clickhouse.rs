@@ -12,6 +12,7 @@ use aws_sdk_s3::config::Credentials;
 use aws_sdk_s3::operation::put_object::PutObjectOutput;
 use aws_sdk_s3::primitives::ByteStream;
 use kirby::clickhouse;
+use kirby::dead_letter::DeadLetter;
 use kirby::s3::S3EventType;
 use kirby::s3::read_object;
 use lambda_runtime::Error;
@@ -56,6 +57,10 @@ fn destination_key(key: &str, target_directory: &str) -> String {
     }
 }
 
+fn rejected_key(result_key: &str) -> String {
+    format!("{}.rejected.jsonl", result_key.trim_end_matches(".json.gz"))
+}
+
 async fn func(event: LambdaEvent<SnsEventObj<S3Event>>) -> Result<(), Error> {
     let s3_client = {
         let config = aws_config::from_env().load().await;
@@ -84,11 +89,18 @@ async fn func(event: LambdaEvent<SnsEventObj<S3Event>>) -> Result<(), Error> {
         Client::new(&config)
     };
 
-    let context = kirby::clickhouse::Context::new(&kirby::full_name_lengths::FULL_NAMES);
+    let mut context = kirby::clickhouse::Context::new(&kirby::full_name_lengths::FULL_NAMES);
     let allow_backfill: bool = env::var("ALLOW_BACKFILL")
         .unwrap_or_else(|_| "false".to_string())
         .parse()
         .unwrap();
+    let dead_letter: bool = env::var("DEAD_LETTER")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse()
+        .unwrap();
+    if dead_letter {
+        context = context.with_dead_letter(DeadLetter::memory());
+    }
 
     for record in event.payload.records {
         for record in record.sns.message.records {
@@ -131,7 +143,7 @@ async fn func(event: LambdaEvent<SnsEventObj<S3Event>>) -> Result<(), Error> {
                 let mut writer =
                     flate2::write::GzEncoder::new(&mut content, flate2::Compression::default());
 
-                clickhouse(&mut writer, reader, &context)?;
+                clickhouse(&mut writer, reader, &key, &context)?;
             }
             let result_key = destination_key(key.as_ref(), target_directory);
             info!(
@@ -140,13 +152,22 @@ async fn func(event: LambdaEvent<SnsEventObj<S3Event>>) -> Result<(), Error> {
                 &result_key
             );
 
-            write_object(
-                &gcp_client,
-                &env::var("CLICKHOUSE_BUCKET").expect("CLICKHOUSE_BUCKET must be set"),
-                &result_key,
-                content,
-            )
-            .await;
+            let clickhouse_bucket =
+                env::var("CLICKHOUSE_BUCKET").expect("CLICKHOUSE_BUCKET must be set");
+            write_object(&gcp_client, &clickhouse_bucket, &result_key, content).await;
+
+            if let Some(dead_letter) = &context.dead_letter {
+                let rejected = dead_letter.take();
+                if !rejected.is_empty() {
+                    let rejected_key = rejected_key(&result_key);
+                    info!(
+                        "{} uploading rejected lines to {}",
+                        time::now_utc().rfc3339(),
+                        &rejected_key
+                    );
+                    write_object(&gcp_client, &clickhouse_bucket, &rejected_key, rejected).await;
+                }
+            }
 
             info!("{} done with {}", time::now_utc().rfc3339(), &key);
         }
@@ -195,4 +216,14 @@ mod test {
             "incremental/2025/2025-04-01T04:00:00.000-W_DzA6b6s9QaCDzkOgDj.json.gz"
         );
     }
+
+    #[test]
+    fn test_rejected_key() {
+        assert_eq!(
+            rejected_key(
+                "incremental/2025/04/01/2025-04-01T04:00:00.000-W_DzA6b6s9QaCDzkOgDj.json.gz"
+            ),
+            "incremental/2025/04/01/2025-04-01T04:00:00.000-W_DzA6b6s9QaCDzkOgDj.rejected.jsonl"
+        );
+    }
 }
@@ -1,6 +1,3 @@
-#[macro_use]
-extern crate serde_json;
-
 extern crate kirby;
 
 use aws_config::meta::region::RegionProviderChain;
@@ -16,6 +13,9 @@ use percent_encoding::percent_decode;
 use std::env;
 
 use kirby::Options;
+use kirby::dead_letter::DeadLetter;
+use kirby::identifier::IdentifierStrategy;
+use kirby::output::{self, Report};
 use kirby::stream_stats;
 
 async fn write_object<B>(client: &Client, bucket_name: &str, key: &str, body: B) -> PutObjectOutput
@@ -42,10 +42,53 @@ async fn func(event: LambdaEvent<SnsEventObj<S3Event>>) -> Result<(), Error> {
         .parse()
         .unwrap();
 
+    let lenient: bool = env::var("LENIENT")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse()
+        .unwrap();
+
+    let dead_letter: bool = env::var("DEAD_LETTER")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse()
+        .unwrap();
+
+    let details: bool = env::var("DETAILS")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse()
+        .unwrap();
+
+    let identifier = env::var("IDENTIFIER").unwrap_or_else(|_| "raw".to_string());
+    let secret = env::var("KIRBY_IDENTIFIER_SECRET").ok();
+    let identifier = IdentifierStrategy::new(&identifier, secret.as_deref())?;
+
+    let traffic: bool = env::var("TRAFFIC")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse()
+        .unwrap();
+
+    let responses: bool = env::var("RESPONSES")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse()
+        .unwrap();
+
+    let rollups: bool = env::var("ROLLUPS")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse()
+        .unwrap();
+
+    let unique_by = env::var("UNIQUE_BY")
+        .unwrap_or_else(|_| "ip".to_string())
+        .parse()?;
+
     let opts = Options {
-        paths: vec![],
-        verbose: false,
-        unknown: false,
+        identifier,
+        unique_by,
+        traffic,
+        responses,
+        rollups,
+        lenient,
+        dead_letter: dead_letter.then(DeadLetter::memory),
+        ..Default::default()
     };
 
     for record in event.payload.records {
@@ -84,7 +127,12 @@ async fn func(event: LambdaEvent<SnsEventObj<S3Event>>) -> Result<(), Error> {
             let reader = read_object(&client, bucket_name, &key).await;
 
             info!("{} calculating stats...", time::now_utc().rfc3339());
-            let content = stream_stats(reader, &opts);
+            // Each object's stats are uploaded without limits, since values folded into
+            // `__other__` can't be told apart again when the objects are merged later
+            let stats = stream_stats(reader, &key, &opts);
+            if stats.skipped != Default::default() {
+                warn!("skipped lines in {}: {:?}", &key, stats.skipped);
+            }
 
             let result_key = [&key, ".json"]
                 .concat()
@@ -94,13 +142,35 @@ async fn func(event: LambdaEvent<SnsEventObj<S3Event>>) -> Result<(), Error> {
                 time::now_utc().rfc3339(),
                 &result_key
             );
-            write_object(
-                &client,
-                bucket_name,
-                &result_key,
-                json!(content).to_string().into_bytes(),
-            )
-            .await;
+            let mut json = Vec::new();
+            if details {
+                // The same shape `kirby` prints, so skipped lines are merged with the stats
+                let report = Report {
+                    bucket: stats.bucket,
+                    database: None,
+                    files: &[key.to_string()],
+                    ran_at: format!("{}", time::now_utc().rfc3339()),
+                    skipped: Some(&stats.skipped),
+                    stats: &stats.times,
+                };
+                output::write_json(&mut json, &report).expect("couldn't write stats");
+            } else {
+                serde_json::to_writer(&mut json, &stats.times).expect("couldn't write stats");
+            }
+            write_object(&client, bucket_name, &result_key, json).await;
+
+            if let Some(dead_letter) = &opts.dead_letter {
+                let rejected = dead_letter.take();
+                if !rejected.is_empty() {
+                    let rejected_key = [&result_key, ".rejected.jsonl"].concat();
+                    info!(
+                        "{} uploading rejected lines to {}",
+                        time::now_utc().rfc3339(),
+                        &rejected_key
+                    );
+                    write_object(&client, bucket_name, &rejected_key, rejected).await;
+                }
+            }
 
             info!("{} done with {}", time::now_utc().rfc3339(), &key);
         }
@@ -5,35 +5,204 @@ extern crate kirby;
 extern crate rayon;
 extern crate time;
 
-use argparse::{ArgumentParser, Collect, StoreTrue};
-use kirby::Options;
+use argparse::{ArgumentParser, Collect, Store, StoreOption, StoreTrue};
+use kirby::bucket::Bucket;
+use kirby::dead_letter::DeadLetter;
+use kirby::filter::Filter;
+use kirby::identifier::IdentifierStrategy;
+use kirby::output::{self, Format, Report};
+use kirby::rules::Rules;
+use kirby::sqlite::{Database, Query};
+use kirby::{Options, Stats};
 use rayon::prelude::*;
+use std::env;
+use std::io::{stderr, stdout};
+use std::process;
+use std::sync::Mutex;
 
 fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some(command @ ("query" | "merge" | "diff")) => {
+            let run = match command {
+                "query" => query,
+                "merge" => merge,
+                _ => diff,
+            };
+            let command = args.remove(1);
+            args[0] = format!("{} {}", args[0], command);
+            run(args)
+        }
+        _ => count(args),
+    }
+}
+
+fn parse_or_exit(ap: &ArgumentParser, args: Vec<String>) {
+    if let Err(code) = ap.parse(args, &mut stdout(), &mut stderr()) {
+        process::exit(code);
+    }
+}
+
+/// Prints stats in the chosen format. Only the JSON has room for the details of the run.
+fn print(format: Format, report: &Report) {
+    let written = match format {
+        Format::Json => output::write_json(stdout().lock(), report),
+        Format::Ndjson => output::write_ndjson(stdout().lock(), report.stats),
+        Format::Csv => output::write_csv(stdout().lock(), report.stats),
+        Format::Parquet => output::write_parquet(stdout(), report.stats),
+    };
+    written.expect("couldn't write output");
+}
+
+fn count(args: Vec<String>) {
+    let mut dead_letter: Option<String> = None;
+    let mut identifier = "raw".to_string();
+    let mut rules: Option<String> = None;
+    let mut limits: Vec<String> = Vec::new();
+    let mut format = Format::Json;
+    let mut sqlite: Option<String> = None;
+    let mut filter: Option<String> = None;
     let mut opts = Options {
         paths: ["test/sample_10.log".to_string()].to_vec(),
-        unknown: false,
-        verbose: false,
+        ..Default::default()
     };
 
     {
         let mut ap = ArgumentParser::new();
-        ap.set_description("Parse a RubyGems.org Fastly JSON log file.");
+        ap.set_description(
+            "Parse a RubyGems.org Fastly JSON log file. Run `kirby query --help` to read \
+             stats back out of a database written with --sqlite, `kirby merge --help` to \
+             combine stats that were printed as JSON, or `kirby diff --help` to compare them.",
+        );
         ap.refer(&mut opts.unknown).add_option(
             &["-u", "--unknown"],
             StoreTrue,
             "Print only unrecognized user agent strings",
         );
+        ap.refer(&mut opts.exact).add_option(
+            &["--exact"],
+            StoreTrue,
+            "Count uniques exactly instead of with HyperLogLog sketches",
+        );
+        ap.refer(&mut identifier).add_option(
+            &["--identifier"],
+            Store,
+            "Count uniques by raw IP (default), truncate to /24 or /64, or hash with \
+             KIRBY_IDENTIFIER_SECRET and a daily salt",
+        );
+        ap.refer(&mut opts.unique_by).add_option(
+            &["--unique-by"],
+            Store,
+            "Count a user as an ip (default), an ip+ua pair, or a bundler-uid",
+        );
+        ap.refer(&mut rules).add_option(
+            &["--rules"],
+            StoreOption,
+            "JSON file of rules for which requests are counted",
+        );
+        ap.refer(&mut opts.bucket).add_option(
+            &["-b", "--bucket"],
+            Store,
+            "Time bucket to count in: hour, day (default), week, or month",
+        );
+        ap.refer(&mut filter).add_option(
+            &["--filter"],
+            StoreOption,
+            "Count only lines that match an expression, like \
+             'client_country == \"Germany\" && ua.ci != null'",
+        );
+        ap.refer(&mut opts.pivots).add_option(
+            &["-p", "--pivot"],
+            Collect,
+            "Also count combinations of fields, like ruby,bundler (repeatable)",
+        );
+        ap.refer(&mut opts.gems).add_option(
+            &["-g", "--gems"],
+            StoreTrue,
+            "Also count downloads per gem and gem version",
+        );
+        ap.refer(&mut opts.traffic).add_option(
+            &["-t", "--traffic"],
+            StoreTrue,
+            "Also sum bytes served and response time percentiles for every count",
+        );
+        ap.refer(&mut opts.responses).add_option(
+            &["-r", "--responses"],
+            StoreTrue,
+            "Also count response statuses and cache states over every request",
+        );
+        ap.refer(&mut opts.rollups).add_option(
+            &["--rollups"],
+            StoreTrue,
+            "Also count ruby, rubygems, and bundler by major and minor version",
+        );
+        ap.refer(&mut limits).add_option(
+            &["--limit"],
+            Collect,
+            "Keep only the top N values of a field, like platform=50, or of every field, \
+             folding the rest into __other__ (repeatable)",
+        );
+        ap.refer(&mut format).add_option(
+            &["-f", "--format"],
+            Store,
+            "Output format: json (default), or ndjson, csv, or parquet rows of date, field, \
+             value, total, and unique",
+        );
+        ap.refer(&mut sqlite).add_option(
+            &["--sqlite"],
+            StoreOption,
+            "Also store each file's stats, without traffic, in this SQLite database, replacing \
+             what the file stored before",
+        );
+        ap.refer(&mut opts.lenient).add_option(
+            &["-l", "--lenient"],
+            StoreTrue,
+            "Skip lines that can't be parsed instead of stopping",
+        );
+        ap.refer(&mut dead_letter).add_option(
+            &["--dead-letter"],
+            StoreOption,
+            "Write lines that can't be parsed to this file, as JSON",
+        );
         ap.refer(&mut opts.verbose)
             .add_option(&["-v", "--verbose"], StoreTrue, "Be verbose");
         ap.refer(&mut opts.paths).add_argument(
             "FILE",
             Collect,
-            "Paths to the log file(s) to process",
+            "Log files, directories, or glob patterns to process, or - for stdin",
         );
-        ap.parse_args_or_exit();
+        parse_or_exit(&ap, args);
+    }
+
+    let secret = env::var("KIRBY_IDENTIFIER_SECRET").ok();
+    opts.identifier =
+        IdentifierStrategy::new(&identifier, secret.as_deref()).unwrap_or_else(|e| panic!("{}", e));
+
+    for pivot in &opts.pivots {
+        pivot.check(&opts).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    for limit in &limits {
+        opts.limits.add(limit).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    if let Some(expr) = filter {
+        let filter = Filter::for_stats(&expr).unwrap_or_else(|e| panic!("invalid filter: {}", e));
+        opts.filter = Some(filter);
+    }
+
+    if let Some(path) = rules {
+        opts.rules = Rules::load(&path).unwrap_or_else(|e| panic!("couldn't load {}: {}", path, e));
+    }
+
+    if let Some(path) = dead_letter {
+        let dead_letter =
+            DeadLetter::create(&path).unwrap_or_else(|e| panic!("couldn't create {}: {}", path, e));
+        opts.dead_letter = Some(dead_letter);
     }
 
+    opts.paths = kirby::file::expand_paths(&opts.paths).unwrap_or_else(|e| panic!("{}", e));
+
     if opts.unknown {
         opts.paths
             .par_iter()
@@ -41,17 +210,267 @@ fn main() {
         return;
     }
 
-    let stats = opts
+    // Stats are stored by file, and stdin has no name that tells one run's apart from another's
+    if sqlite.is_some() && opts.paths.iter().any(|path| path == kirby::file::STDIN) {
+        panic!("--sqlite can't store stats read from stdin, since they have no file name");
+    }
+
+    let database = sqlite.as_ref().map(|path| {
+        let database =
+            Database::open(path).unwrap_or_else(|e| panic!("couldn't open {}: {}", path, e));
+        Mutex::new(database)
+    });
+
+    let mut stats = opts
         .paths
         .par_iter()
-        .map(|path| kirby::file_stats(path, &opts))
-        .reduce_with(kirby::combine_stats)
+        .map(|path| {
+            let stats = kirby::file_stats(path, &opts)
+                .unwrap_or_else(|e| panic!("couldn't read {}: {}", path, e));
+            if let Some(database) = &database {
+                database
+                    .lock()
+                    .unwrap()
+                    .write(path, &stats)
+                    .unwrap_or_else(|e| panic!("couldn't store stats for {}: {}", path, e));
+            }
+            stats
+        })
+        .reduce_with(kirby::Stats::combine)
         .unwrap();
+    stats.limit(&opts.limits);
 
-    let output = json!({
-      "ran_at": format!("{}", time::now_utc().rfc3339()),
-      "stats": stats,
-      "files": opts.paths,
-    });
-    println!("{}", output);
+    let report = Report {
+        bucket: stats.bucket,
+        database: None,
+        files: &opts.paths,
+        ran_at: format!("{}", time::now_utc().rfc3339()),
+        skipped: Some(&stats.skipped),
+        stats: &stats.times,
+    };
+    print(format, &report);
+
+    if let Some(dead_letter) = &opts.dead_letter {
+        dead_letter.flush().expect("couldn't write rejected lines");
+    }
+}
+
+fn query(args: Vec<String>) {
+    let mut path = String::new();
+    let mut query = Query::default();
+    let mut bucket: Option<String> = None;
+    let mut limits: Vec<String> = Vec::new();
+    let mut format = Format::Json;
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Add up the stats stored in a SQLite database by `kirby --sqlite`, across every \
+             file that was stored.",
+        );
+        ap.refer(&mut query.fields).add_option(
+            &["--field"],
+            Collect,
+            "Read only this field or pivot, like ruby or ruby+bundler (repeatable)",
+        );
+        ap.refer(&mut query.from).add_option(
+            &["--from"],
+            StoreOption,
+            "Read only dates from this one on, like 2024-01-01",
+        );
+        ap.refer(&mut query.to).add_option(
+            &["--to"],
+            StoreOption,
+            "Read only dates up to and including this one, like 2024-01-31 or 2024-01",
+        );
+        ap.refer(&mut bucket).add_option(
+            &["-b", "--bucket"],
+            StoreOption,
+            "Combine the stored stats into week or month buckets",
+        );
+        ap.refer(&mut limits).add_option(
+            &["--limit"],
+            Collect,
+            "Keep only the top N values of a field, like platform=50, or of every field \
+             (repeatable)",
+        );
+        ap.refer(&mut format).add_option(
+            &["-f", "--format"],
+            Store,
+            "Output format: json (default), ndjson, csv, or parquet",
+        );
+        ap.refer(&mut path)
+            .add_argument("DATABASE", Store, "The SQLite database to read")
+            .required();
+        parse_or_exit(&ap, args);
+    }
+
+    query.bucket = bucket.map(|b| b.parse().unwrap_or_else(|e| panic!("{}", e)));
+    let mut opts = Options::default();
+    for limit in &limits {
+        opts.limits.add(limit).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    let database =
+        Database::open(&path).unwrap_or_else(|e| panic!("couldn't open {}: {}", path, e));
+    let (times, sources) = match (database.query(&query), database.sources()) {
+        (Ok(times), Ok(sources)) => (times, sources),
+        (Err(e), _) | (_, Err(e)) => panic!("couldn't read {}: {}", path, e),
+    };
+    let mut stats = Stats {
+        times,
+        ..Default::default()
+    };
+    stats.limit(&opts.limits);
+
+    let report = Report {
+        bucket: query.bucket,
+        database: Some(&path),
+        files: &sources,
+        ran_at: format!("{}", time::now_utc().rfc3339()),
+        skipped: None,
+        stats: &stats.times,
+    };
+    print(format, &report);
+}
+
+fn read_stats(path: &str) -> Stats {
+    kirby::file::reader(path, &Options::default())
+        .map_err(|e| e.to_string())
+        .and_then(|reader| kirby::read_stats(reader).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| panic!("couldn't read {}: {}", path, e))
+}
+
+fn merge(args: Vec<String>) {
+    let mut paths: Vec<String> = Vec::new();
+    let mut bucket: Option<String> = None;
+    let mut limits: Vec<String> = Vec::new();
+    let mut format = Format::Json;
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Combine stats JSON files, printed by `kirby` or uploaded by the S3 Lambda, into \
+             one. Uniques are merged with their sketches, so stats counted with --exact can't \
+             be combined.",
+        );
+        ap.refer(&mut bucket).add_option(
+            &["-b", "--bucket"],
+            StoreOption,
+            "Combine the stats into week or month buckets, like daily stats into a monthly \
+             rollup",
+        );
+        ap.refer(&mut limits).add_option(
+            &["--limit"],
+            Collect,
+            "Keep only the top N values of a field, like platform=50, or of every field \
+             (repeatable)",
+        );
+        ap.refer(&mut format).add_option(
+            &["-f", "--format"],
+            Store,
+            "Output format: json (default), ndjson, csv, or parquet",
+        );
+        ap.refer(&mut paths)
+            .add_argument(
+                "FILE",
+                Collect,
+                "Stats files, directories, or glob patterns to combine, or - for stdin",
+            )
+            .required();
+        parse_or_exit(&ap, args);
+    }
+
+    let mut opts = Options::default();
+    for limit in &limits {
+        opts.limits.add(limit).unwrap_or_else(|e| panic!("{}", e));
+    }
+    let paths = kirby::file::expand_paths(&paths).unwrap_or_else(|e| panic!("{}", e));
+
+    let bucket: Option<Bucket> = bucket.map(|b| b.parse().unwrap_or_else(|e| panic!("{}", e)));
+    let mut stats = paths
+        .par_iter()
+        .map(|path| {
+            let mut stats = read_stats(path);
+            if let Some(bucket) = bucket {
+                stats
+                    .rebucket(bucket)
+                    .unwrap_or_else(|e| panic!("couldn't rebucket {}: {}", path, e));
+            }
+            stats
+        })
+        .reduce_with(Stats::combine)
+        .unwrap_or_default();
+    stats.limit(&opts.limits);
+
+    let report = Report {
+        bucket: stats.bucket,
+        database: None,
+        files: &paths,
+        ran_at: format!("{}", time::now_utc().rfc3339()),
+        skipped: Some(&stats.skipped),
+        stats: &stats.times,
+    };
+    print(format, &report);
+}
+
+fn diff(args: Vec<String>) {
+    let mut before = String::new();
+    let mut after = String::new();
+    let mut fields: Vec<String> = Vec::new();
+    let mut top: Option<usize> = None;
+    let mut table = false;
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Compare two stats JSON files, like one week and the next, showing each value's \
+             change in total and in share of its field, and which values are new or gone. \
+             Every time bucket in a file is added up before comparing.",
+        );
+        ap.refer(&mut fields).add_option(
+            &["--field"],
+            Collect,
+            "Compare only this field or pivot, like ruby or ruby+bundler (repeatable)",
+        );
+        ap.refer(&mut top).add_option(
+            &["--top"],
+            StoreOption,
+            "Show only the N values of each field whose share changed the most",
+        );
+        ap.refer(&mut table).add_option(
+            &["--table"],
+            StoreTrue,
+            "Print a table for each field instead of JSON",
+        );
+        ap.refer(&mut before)
+            .add_argument("BEFORE", Store, "The earlier stats file")
+            .required();
+        ap.refer(&mut after)
+            .add_argument("AFTER", Store, "The later stats file")
+            .required();
+        parse_or_exit(&ap, args);
+    }
+
+    let mut diff = kirby::diff::diff(&read_stats(&before).times, &read_stats(&after).times);
+    if !fields.is_empty() {
+        diff.fields.retain(|field| fields.contains(&field.field));
+    }
+    if let Some(top) = top {
+        for field in &mut diff.fields {
+            field.changes.truncate(top);
+        }
+    }
+
+    if table {
+        kirby::diff::write_table(stdout().lock(), &diff).expect("couldn't write output");
+    } else {
+        let output = json!({
+          "ran_at": format!("{}", time::now_utc().rfc3339()),
+          "before": before,
+          "after": after,
+          "fields": diff.fields,
+        });
+        println!("{}", output);
+    }
 }
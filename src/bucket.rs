@@ -0,0 +1,203 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// How finely stats are split up over time. Each request is counted under the key of the
+/// bucket its timestamp falls in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    /// `2018-04-16 04:00`
+    Hour,
+    /// `2018-04-16`
+    #[default]
+    Day,
+    /// `2018-04-16`, the Monday that starts the (ISO 8601) week
+    Week,
+    /// `2018-04`
+    Month,
+}
+
+impl Bucket {
+    /// Builds the key for a Fastly timestamp such as `2018-04-16 04:59:59`. Returns `None`
+    /// if the timestamp is too short or isn't a date.
+    pub fn key(&self, timestamp: &str) -> Option<String> {
+        match self {
+            Bucket::Hour => {
+                let (date, hour) = (timestamp.get(..10)?, timestamp.get(11..13)?);
+                Some(format!("{} {}:00", date, hour))
+            }
+            Bucket::Day => timestamp.get(..10).map(String::from),
+            Bucket::Week => {
+                let days = days_from_civil(parse_date(timestamp)?);
+                // 1970-01-01 was a Thursday
+                let monday = days - (days + 3).rem_euclid(7);
+                let (y, m, d) = civil_from_days(monday);
+                Some(format!("{:04}-{:02}-{:02}", y, m, d))
+            }
+            Bucket::Month => timestamp.get(..7).map(String::from),
+        }
+    }
+
+    /// Guesses the bucket of a key from its shape. Week keys look like day keys, so this
+    /// is only for stats that didn't say what they were counted in, which are days.
+    pub fn of_key(key: &str) -> Option<Bucket> {
+        match key.len() {
+            16 => Some(Bucket::Hour),
+            10 => Some(Bucket::Day),
+            7 => Some(Bucket::Month),
+            _ => None,
+        }
+    }
+
+    /// Turns the key of a `from` bucket into the key of the bucket it falls in. Only
+    /// buckets that fit inside one of these can be turned into them, so weeks, which can
+    /// start in one month and end in the next, can't become months.
+    pub fn rekey(&self, from: Bucket, key: &str) -> Result<String, String> {
+        let looks_like = match from {
+            Bucket::Week => Bucket::Day,
+            from => from,
+        };
+        let invalid = || format!("{:?} isn't a {} key", key, from);
+        if Bucket::of_key(key) != Some(looks_like) {
+            return Err(invalid());
+        }
+        match (from, self) {
+            (from, to) if from == *to => Ok(key.to_string()),
+            (Bucket::Hour, _) | (Bucket::Day, Bucket::Week | Bucket::Month) => {
+                self.key(key).ok_or_else(invalid)
+            }
+            (from, to) => Err(format!("can't turn {} buckets into {} buckets", from, to)),
+        }
+    }
+}
+
+impl FromStr for Bucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hour" => Ok(Bucket::Hour),
+            "day" => Ok(Bucket::Day),
+            "week" => Ok(Bucket::Week),
+            "month" => Ok(Bucket::Month),
+            _ => Err(format!("unknown bucket {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+        })
+    }
+}
+
+fn parse_date(timestamp: &str) -> Option<(i64, u32, u32)> {
+    let date = timestamp.get(..10)?;
+    let mut parts = date.splitn(3, '-');
+    let y = parts.next()?.parse().ok()?;
+    let m = parts.next()?.parse().ok()?;
+    let d = parts.next()?.parse().ok()?;
+    Some((y, m, d))
+}
+
+// Howard Hinnant's days_from_civil and civil_from_days, counting days since 1970-01-01 in
+// the proleptic Gregorian calendar.
+fn days_from_civil((y, m, d): (i64, u32, u32)) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_keys() {
+        let timestamp = "2018-04-16 04:59:59";
+        assert_eq!(Bucket::Hour.key(timestamp).unwrap(), "2018-04-16 04:00");
+        assert_eq!(Bucket::Day.key(timestamp).unwrap(), "2018-04-16");
+        assert_eq!(Bucket::Week.key(timestamp).unwrap(), "2018-04-16");
+        assert_eq!(Bucket::Month.key(timestamp).unwrap(), "2018-04");
+
+        assert_eq!(
+            Bucket::Week.key("2018-04-22 23:59:59").unwrap(),
+            "2018-04-16"
+        );
+        assert_eq!(
+            Bucket::Week.key("2018-04-23 00:00:00").unwrap(),
+            "2018-04-23"
+        );
+        assert_eq!(
+            Bucket::Week.key("2021-01-02 12:00:00").unwrap(),
+            "2020-12-28"
+        );
+        assert_eq!(
+            Bucket::Week.key("2024-03-01 12:00:00").unwrap(),
+            "2024-02-26"
+        );
+
+        assert_eq!(Bucket::Day.key("2018-04"), None);
+        assert_eq!(Bucket::Hour.key("2018-04-16"), None);
+        assert_eq!(Bucket::Week.key("yesterday!"), None);
+    }
+
+    #[test]
+    fn test_rekey() {
+        assert_eq!(
+            Bucket::Week.rekey(Bucket::Hour, "2018-04-29 23:00"),
+            Ok("2018-04-23".to_string())
+        );
+        assert_eq!(
+            Bucket::Month.rekey(Bucket::Day, "2018-04-30"),
+            Ok("2018-04".to_string())
+        );
+        assert_eq!(
+            Bucket::Week.rekey(Bucket::Week, "2018-04-30"),
+            Ok("2018-04-30".to_string())
+        );
+        assert_eq!(
+            Bucket::Month.rekey(Bucket::Week, "2018-04-30"),
+            Err("can't turn week buckets into month buckets".to_string())
+        );
+        assert_eq!(
+            Bucket::Day.rekey(Bucket::Week, "2018-04-30"),
+            Err("can't turn week buckets into day buckets".to_string())
+        );
+        assert_eq!(
+            Bucket::Hour.rekey(Bucket::Day, "2018-04-30"),
+            Err("can't turn day buckets into hour buckets".to_string())
+        );
+        assert_eq!(
+            Bucket::Month.rekey(Bucket::Day, "2018-04"),
+            Err("\"2018-04\" isn't a day key".to_string())
+        );
+
+        assert_eq!(Bucket::of_key("2018-04-16 04:00"), Some(Bucket::Hour));
+        assert_eq!(Bucket::of_key("2018-04-16"), Some(Bucket::Day));
+        assert_eq!(Bucket::of_key("2018-04"), Some(Bucket::Month));
+        assert_eq!(Bucket::of_key("2018"), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! Splitting a log stream into chunks of whole lines, so that the chunks can be parsed in
+//! parallel while the stream itself is read in order.
+
+use std::io::{BufRead, Error, Result};
+
+/// How many bytes of log lines go into each chunk, before finishing the last line.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+pub struct Chunk {
+    /// The line number of the first line, counting from 1
+    pub first_line: usize,
+    data: Vec<u8>,
+}
+
+impl Chunk {
+    /// Each line with its line number. Lines keep their trailing newline, and lines that
+    /// aren't UTF-8 are errors.
+    pub fn lines(&self) -> impl Iterator<Item = (usize, std::result::Result<&str, &[u8]>)> {
+        self.data
+            .split_inclusive(|&b| b == b'\n')
+            .enumerate()
+            .map(|(i, line)| {
+                (
+                    self.first_line + i,
+                    std::str::from_utf8(line).map_err(|_| line),
+                )
+            })
+    }
+}
+
+pub struct ChunkReader<'a> {
+    stream: Box<dyn BufRead + 'a>,
+    size: usize,
+    lines: usize,
+    error: Option<Error>,
+}
+
+impl<'a> ChunkReader<'a> {
+    pub fn new(stream: Box<dyn BufRead + 'a>, size: usize) -> Self {
+        ChunkReader {
+            stream,
+            size,
+            lines: 0,
+            error: None,
+        }
+    }
+
+    /// The number of lines read so far.
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// Reads up to `count` chunks, returning none at the end of the stream. If reading
+    /// fails partway, the chunks read before that are returned first, and the error on
+    /// the next call.
+    pub fn next_batch(&mut self, count: usize) -> Result<Vec<Chunk>> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let mut batch = Vec::with_capacity(count);
+        while batch.len() < count {
+            match self.next_chunk() {
+                Ok(Some(chunk)) => batch.push(chunk),
+                Ok(None) => break,
+                Err(e) if batch.is_empty() => return Err(e),
+                Err(e) => {
+                    self.error = Some(e);
+                    break;
+                }
+            }
+        }
+        Ok(batch)
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        let mut data = Vec::with_capacity(self.size.min(CHUNK_SIZE) + 4096);
+        while data.len() < self.size {
+            let buf = self.stream.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let n = buf.len().min(self.size - data.len());
+            data.extend_from_slice(&buf[..n]);
+            self.stream.consume(n);
+        }
+        if data.is_empty() {
+            return Ok(None);
+        }
+        if !data.ends_with(b"\n") {
+            self.stream.read_until(b'\n', &mut data)?;
+        }
+
+        let first_line = self.lines + 1;
+        self.lines += data.split_inclusive(|&b| b == b'\n').count();
+        Ok(Some(Chunk { first_line, data }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_hold_whole_lines() {
+        let log = b"one\ntwo\nthree\n\nfive\xff\nsix";
+        let mut reader = ChunkReader::new(Box::new(&log[..]), 5);
+        let mut lines = Vec::new();
+        loop {
+            let batch = reader.next_batch(2).unwrap();
+            if batch.is_empty() {
+                break;
+            }
+            for chunk in &batch {
+                lines.extend(
+                    chunk
+                        .lines()
+                        .map(|(n, line)| (n, line.map(String::from).map_err(|l| l.len()))),
+                );
+            }
+        }
+        assert_eq!(
+            lines,
+            [
+                (1, Ok("one\n".to_string())),
+                (2, Ok("two\n".to_string())),
+                (3, Ok("three\n".to_string())),
+                (4, Ok("\n".to_string())),
+                (5, Err(6)),
+                (6, Ok("six".to_string())),
+            ]
+        );
+        assert_eq!(reader.lines(), 6);
+    }
+}
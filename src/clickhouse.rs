@@ -2,9 +2,15 @@ use std::collections::HashMap;
 
 use regex::Regex;
 
+use crate::dead_letter::DeadLetter;
+use crate::filter::Filter;
+
 pub struct Context<'a> {
     pub full_name_lengths: &'a HashMap<&'a str, (u8, u8)>,
     pub download_pattern: Regex,
+    pub dead_letter: Option<DeadLetter>,
+    /// Only rows that match are written.
+    pub filter: Option<Filter>,
 }
 
 impl<'a> Context<'a> {
@@ -14,6 +20,56 @@ impl<'a> Context<'a> {
         Context {
             full_name_lengths,
             download_pattern,
+            dead_letter: None,
+            filter: None,
+        }
+    }
+
+    /// Writes records that can't be turned into rows to `dead_letter`, instead of
+    /// stopping with an error.
+    pub fn with_dead_letter(mut self, dead_letter: DeadLetter) -> Self {
+        self.dead_letter = Some(dead_letter);
+        self
+    }
+
+    /// Writes only the rows that match `filter`.
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Returns the full name of the gem downloaded by a request path like
+    /// `/gems/rack-3.1.0.gem`, or `None` if the path isn't a gem download.
+    pub fn full_name<'p>(&self, request_path: &'p str) -> Option<&'p str> {
+        if !self.download_pattern.is_match(request_path) {
+            return None;
+        }
+        Some(&request_path[6..request_path.len() - 4])
+    }
+
+    /// Splits a gem full name like `rack-3.1.0` or `nokogiri-1.16.0-x86_64-linux` into
+    /// its gem name, version, and platform. Names with more than one dash are ambiguous,
+    /// and are looked up in `full_name_lengths`. Returns `None` for unknown names.
+    pub fn split_full_name<'n>(&self, full_name: &'n str) -> Option<(&'n str, &'n str, &'n str)> {
+        let mut parts = full_name.splitn(3, '-');
+        let gem = parts.next();
+        let version = parts.next();
+        if let (Some(gem), Some(version), None) = (gem, version, parts.remainder()) {
+            return Some((gem, version, "ruby"));
         }
+
+        let (name_len, version_len) = self.full_name_lengths.get(full_name)?;
+        let name_end = *name_len as usize;
+        let version_end = name_end + 1 + *version_len as usize;
+        let platform = if version_end < full_name.len() {
+            &full_name[version_end + 1..]
+        } else {
+            "ruby"
+        };
+        Some((
+            &full_name[..name_end],
+            &full_name[name_end + 1..version_end],
+            platform,
+        ))
     }
 }
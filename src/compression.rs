@@ -0,0 +1,182 @@
+//! Decompressing log streams, by looking at their first bytes rather than trusting a file
+//! extension or object key.
+
+use std::io::{BufRead, BufReader, Cursor, Read, Result};
+
+use bzip2::bufread::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use liblzma::bufread::XzDecoder;
+
+/// The most bytes `Compression::sniff` needs to see.
+const MAGIC_LEN: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+impl Compression {
+    /// Recognizes a compressed stream from its magic bytes.
+    pub fn sniff(start: &[u8]) -> Compression {
+        if start.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if start.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if start.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if start.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Wraps a stream in whichever decoder its first bytes call for. Every decoder reads
+/// all of the members, frames, or streams that were concatenated together, not just the
+/// first one.
+pub fn decoder<'a>(mut stream: Box<dyn BufRead + 'a>) -> Result<Box<dyn BufRead + 'a>> {
+    let capacity = 1024 * 1024;
+    let buffered = stream.fill_buf()?.len();
+    let (compression, stream) = if buffered >= MAGIC_LEN || buffered == 0 {
+        (Compression::sniff(stream.fill_buf()?), stream)
+    } else {
+        // Pipes can hand over fewer bytes at a time than the magic numbers are long
+        let mut start = Vec::with_capacity(MAGIC_LEN);
+        while start.len() < MAGIC_LEN {
+            let buf = stream.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let n = buf.len().min(MAGIC_LEN - start.len());
+            start.extend_from_slice(&buf[..n]);
+            stream.consume(n);
+        }
+        let compression = Compression::sniff(&start);
+        let stream: Box<dyn BufRead + 'a> = Box::new(Cursor::new(start).chain(stream));
+        (compression, stream)
+    };
+    Ok(match compression {
+        Compression::None => stream,
+        Compression::Gzip => Box::new(BufReader::with_capacity(
+            capacity,
+            MultiGzDecoder::new(stream),
+        )),
+        Compression::Zstd => Box::new(BufReader::with_capacity(
+            capacity,
+            zstd::Decoder::with_buffer(stream)?,
+        )),
+        Compression::Xz => Box::new(BufReader::with_capacity(
+            capacity,
+            XzDecoder::new_multi_decoder(stream),
+        )),
+        Compression::Bzip2 => Box::new(BufReader::with_capacity(
+            capacity,
+            MultiBzDecoder::new(stream),
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Hands over one byte per read, like a slow pipe.
+    struct Trickle(Vec<u8>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0.remove(0);
+            Ok(1)
+        }
+    }
+
+    fn decompress(bytes: Vec<u8>) -> String {
+        let mut text = String::new();
+        decoder(Box::new(bytes.as_slice()))
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    fn compress(compression: Compression, s: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        match compression {
+            Compression::None => out.extend_from_slice(s.as_bytes()),
+            Compression::Gzip => {
+                let mut e = flate2::write::GzEncoder::new(&mut out, flate2::Compression::fast());
+                e.write_all(s.as_bytes()).unwrap();
+                e.finish().unwrap();
+            }
+            Compression::Zstd => zstd::stream::copy_encode(s.as_bytes(), &mut out, 1).unwrap(),
+            Compression::Xz => {
+                let mut e = liblzma::write::XzEncoder::new(&mut out, 1);
+                e.write_all(s.as_bytes()).unwrap();
+                e.finish().unwrap();
+            }
+            Compression::Bzip2 => {
+                let mut e = bzip2::write::BzEncoder::new(&mut out, bzip2::Compression::fast());
+                e.write_all(s.as_bytes()).unwrap();
+                e.finish().unwrap();
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_concatenated_streams() {
+        let (first, second) = ("{\"one\":1}\n", "{\"two\":2}\n");
+        let both = first.to_string() + second;
+
+        for compression in [
+            Compression::Gzip,
+            Compression::Zstd,
+            Compression::Xz,
+            Compression::Bzip2,
+        ] {
+            let concatenated =
+                [compress(compression, first), compress(compression, second)].concat();
+            assert_eq!(Compression::sniff(&concatenated), compression);
+            assert_eq!(decompress(concatenated), both, "{compression:?}");
+        }
+
+        assert_eq!(Compression::sniff(both.as_bytes()), Compression::None);
+        assert_eq!(decompress(both.clone().into_bytes()), both);
+        assert_eq!(decompress(Vec::new()), "");
+    }
+
+    #[test]
+    fn test_short_reads() {
+        let text = "{\"one\":1}\n";
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Zstd,
+            Compression::Xz,
+            Compression::Bzip2,
+        ] {
+            let stream = BufReader::new(Trickle(compress(compression, text)));
+            let mut decompressed = String::new();
+            decoder(Box::new(stream))
+                .unwrap()
+                .read_to_string(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, text, "{compression:?}");
+        }
+        let mut short = String::new();
+        decoder(Box::new(BufReader::new(Trickle(b"{}".to_vec()))))
+            .unwrap()
+            .read_to_string(&mut short)
+            .unwrap();
+        assert_eq!(short, "{}");
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::mem;
+use std::sync::Mutex;
+
+enum Sink {
+    File(BufWriter<File>),
+    Memory(Vec<u8>),
+}
+
+#[derive(Serialize)]
+struct Rejection<'a> {
+    source: &'a str,
+    line: usize,
+    error: String,
+    record: &'a str,
+}
+
+/// Somewhere to keep the raw log lines that were rejected, one JSON object per line with
+/// the source file, line number, and error, so they can be looked at later. Can be shared
+/// between threads.
+pub struct DeadLetter {
+    sink: Mutex<Sink>,
+}
+
+impl DeadLetter {
+    /// Writes rejected lines to a local file, replacing it if it exists.
+    pub fn create(path: &str) -> Result<Self> {
+        let file = File::create(path)?;
+        Ok(DeadLetter {
+            sink: Mutex::new(Sink::File(BufWriter::new(file))),
+        })
+    }
+
+    /// Keeps rejected lines in memory, to be collected with `take`.
+    pub fn memory() -> Self {
+        DeadLetter {
+            sink: Mutex::new(Sink::Memory(Vec::new())),
+        }
+    }
+
+    pub fn record(
+        &self,
+        source: &str,
+        line: usize,
+        error: &dyn fmt::Display,
+        record: &str,
+    ) -> Result<()> {
+        let rejection = Rejection {
+            source,
+            line,
+            error: error.to_string(),
+            record: record.trim_end_matches(['\r', '\n']),
+        };
+        let mut sink = self.sink.lock().unwrap();
+        let w: &mut dyn Write = match &mut *sink {
+            Sink::File(w) => w,
+            Sink::Memory(w) => w,
+        };
+        serde_json::to_writer(&mut *w, &rejection)?;
+        w.write_all(b"\n")
+    }
+
+    /// Returns the rejected lines kept in memory since the last call, and clears them.
+    /// Always empty when writing to a file.
+    pub fn take(&self) -> Vec<u8> {
+        match &mut *self.sink.lock().unwrap() {
+            Sink::File(_) => Vec::new(),
+            Sink::Memory(buf) => mem::take(buf),
+        }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        match &mut *self.sink.lock().unwrap() {
+            Sink::File(w) => w.flush(),
+            Sink::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for DeadLetter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &*self.sink.lock().unwrap() {
+            Sink::File(_) => f.write_str("DeadLetter(File)"),
+            Sink::Memory(buf) => write!(f, "DeadLetter(Memory, {} bytes)", buf.len()),
+        }
+    }
+}
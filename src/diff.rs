@@ -0,0 +1,278 @@
+//! Comparing two sets of stats, like one week against the next, to see which values are
+//! gaining or losing share and which appeared or went away.
+
+use std::collections::HashMap;
+use std::io::{Result, Write};
+
+use crate::{Counters, TimeMap};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Only counted after
+    New,
+    /// Only counted before
+    Gone,
+    /// Counted in both
+    Kept,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Count {
+    pub total: usize,
+    pub unique: usize,
+    pub share: f64,
+}
+
+/// How one value of a field changed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Change {
+    pub value: String,
+    pub status: Status,
+    pub before: Option<Count>,
+    pub after: Option<Count>,
+    pub total_delta: i64,
+    pub unique_delta: i64,
+    pub share_delta: f64,
+}
+
+/// How one field or pivot changed, with the values whose share changed the most first.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldDiff {
+    pub field: String,
+    pub total_before: usize,
+    pub total_after: usize,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Diff {
+    pub fields: Vec<FieldDiff>,
+}
+
+fn period(times: &TimeMap) -> Counters {
+    times
+        .values()
+        .cloned()
+        .fold(Counters::default(), |mut period, counters| {
+            period.combine(counters);
+            period
+        })
+}
+
+fn counts(field: &str, counters: &Counters) -> (usize, Vec<(String, Count)>) {
+    let Some((_, values)) = counters.histograms().find(|&(name, _)| name == field) else {
+        return (0, Vec::new());
+    };
+    let entries = values.entries();
+    let total = entries.iter().map(|(_, counter)| counter.total).sum();
+    let counts = entries
+        .into_iter()
+        .map(|(value, counter)| {
+            let count = Count {
+                total: counter.total,
+                unique: counter.unique(),
+                share: counter.total as f64 / total as f64,
+            };
+            (value.to_string(), count)
+        })
+        .collect();
+    (total, counts)
+}
+
+/// Compares the stats `after` with the stats `before`, field by field.
+pub fn diff(before: &TimeMap, after: &TimeMap) -> Diff {
+    let (before, after) = (period(before), period(after));
+    // Every value of both periods, in the same order as the output
+    let mut both = before.clone();
+    both.combine(after.clone());
+    let mut fields: Vec<&str> = before.iter().map(|(name, _)| name).collect();
+    for (name, _) in after.iter() {
+        if !fields.contains(&name) {
+            fields.push(name);
+        }
+    }
+
+    let fields = fields
+        .into_iter()
+        .filter_map(|field| {
+            let (total_before, before) = counts(field, &before);
+            let (total_after, after) = counts(field, &after);
+            if before.is_empty() && after.is_empty() {
+                return None;
+            }
+
+            let before: HashMap<&str, Count> = before
+                .iter()
+                .map(|(v, count)| (v.as_str(), *count))
+                .collect();
+            let after: HashMap<&str, Count> = after
+                .iter()
+                .map(|(v, count)| (v.as_str(), *count))
+                .collect();
+            let (_, values) = counts(field, &both);
+            let mut changes: Vec<Change> = values
+                .iter()
+                .map(|(value, _)| {
+                    let value = value.as_str();
+                    change(value, before.get(value).copied(), after.get(value).copied())
+                })
+                .collect();
+            // The sort is stable, so values with the same change stay in value order
+            changes.sort_by(|a, b| b.share_delta.abs().total_cmp(&a.share_delta.abs()));
+
+            Some(FieldDiff {
+                field: field.to_string(),
+                total_before,
+                total_after,
+                changes,
+            })
+        })
+        .collect();
+    Diff { fields }
+}
+
+fn change(value: &str, before: Option<Count>, after: Option<Count>) -> Change {
+    let status = match (before, after) {
+        (None, _) => Status::New,
+        (_, None) => Status::Gone,
+        _ => Status::Kept,
+    };
+    let (b, a) = (before.unwrap_or_default(), after.unwrap_or_default());
+    Change {
+        value: value.to_string(),
+        status,
+        before,
+        after,
+        total_delta: a.total as i64 - b.total as i64,
+        unique_delta: a.unique as i64 - b.unique as i64,
+        share_delta: a.share - b.share,
+    }
+}
+
+/// Writes a diff as a table for each field, marking new values with `+` and gone values
+/// with `-`. Shares are percentages, and their changes are in percentage points.
+pub fn write_table<W: Write>(mut w: W, diff: &Diff) -> Result<()> {
+    for (i, field) in diff.fields.iter().enumerate() {
+        if i > 0 {
+            writeln!(w)?;
+        }
+        let delta = field.total_after as i64 - field.total_before as i64;
+        writeln!(
+            w,
+            "{} ({} → {}, {:+})",
+            field.field, field.total_before, field.total_after, delta
+        )?;
+
+        let width = field
+            .changes
+            .iter()
+            .map(|change| change.value.chars().count())
+            .fold("value".len(), usize::max);
+        writeln!(
+            w,
+            "  {:<width$}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}",
+            "value", "before", "after", "delta", "uniques", "before %", "after %", "delta pp"
+        )?;
+        for change in &field.changes {
+            let marker = match change.status {
+                Status::New => '+',
+                Status::Gone => '-',
+                Status::Kept => ' ',
+            };
+            let (before, after) = (
+                change.before.unwrap_or_default(),
+                change.after.unwrap_or_default(),
+            );
+            writeln!(
+                w,
+                "{} {:<width$}  {:>8}  {:>8}  {:>+8}  {:>+8}  {:>7.1}%  {:>7.1}%  {:>+8.1}",
+                marker,
+                change.value,
+                before.total,
+                after.total,
+                change.total_delta,
+                change.unique_delta,
+                before.share * 100.0,
+                after.share * 100.0,
+                change.share_delta * 100.0,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ValueUniqueCounter;
+
+    fn times(days: &[(&str, &str, usize)]) -> TimeMap {
+        let mut times = TimeMap::new();
+        for &(day, value, total) in days {
+            let counter = ValueUniqueCounter {
+                total,
+                ..Default::default()
+            };
+            times
+                .entry(day.to_string())
+                .or_default()
+                .add("ruby", value, &counter);
+        }
+        times
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = times(&[
+            ("2024-01-01", "2.7.8", 2),
+            ("2024-01-02", "2.7.8", 1),
+            ("2024-01-02", "3.3.0", 1),
+        ]);
+        let after = times(&[("2024-01-08", "3.3.0", 2), ("2024-01-08", "3.4.0", 2)]);
+        let diff = diff(&before, &after);
+
+        assert_eq!(diff.fields.len(), 1);
+        let ruby = &diff.fields[0];
+        assert_eq!((ruby.total_before, ruby.total_after), (4, 4));
+        let changes: Vec<_> = ruby
+            .changes
+            .iter()
+            .map(|c| (c.value.as_str(), c.status, c.total_delta, c.share_delta))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("2.7.8", Status::Gone, -3, -0.75),
+                ("3.4.0", Status::New, 2, 0.5),
+                ("3.3.0", Status::Kept, 1, 0.25),
+            ]
+        );
+        assert_eq!(ruby.changes[1].before, None);
+        assert_eq!(ruby.changes[1].after.unwrap().share, 0.5);
+
+        let mut table = Vec::new();
+        write_table(&mut table, &diff).unwrap();
+        assert_eq!(
+            String::from_utf8(table).unwrap(),
+            "ruby (4 → 4, +0)\n\
+             \x20 value    before     after     delta   uniques  before %   after %  delta pp\n\
+             - 2.7.8         3         0        -3        +0     75.0%      0.0%     -75.0\n\
+             + 3.4.0         0         2        +2        +0      0.0%     50.0%     +50.0\n\
+             \x20 3.3.0         1         2        +1        +0     25.0%     50.0%     +25.0\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_ties_are_in_version_order() {
+        let before = times(&[("2024-01-01", "1.0", 1), ("2024-01-01", "2.9.0", 1)]);
+        let after = times(&[("2024-01-08", "1.0", 1), ("2024-01-08", "2.10.0", 1)]);
+        let diff = diff(&before, &after);
+        let values: Vec<_> = diff.fields[0]
+            .changes
+            .iter()
+            .map(|c| c.value.as_str())
+            .collect();
+        assert_eq!(values, ["2.9.0", "2.10.0", "1.0"]);
+    }
+}
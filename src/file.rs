@@ -1,24 +1,124 @@
-use flate2::read::GzDecoder;
-use std::ffi::OsStr;
-use std::fs::File;
-use std::io::BufRead;
-use std::io::BufReader;
-use std::path::Path;
-
-pub fn reader(filename: &str, opts: &super::Options) -> Box<dyn BufRead> {
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use crate::compression;
+
+/// The path that means standard input.
+pub const STDIN: &str = "-";
+
+/// Opens a log file, or standard input for `-`, decompressing it if needed.
+pub fn reader(filename: &str, opts: &super::Options) -> Result<Box<dyn BufRead>> {
     if opts.verbose {
         println!("Opening log file {}", filename);
     }
 
-    let path = Path::new(filename);
-    let file = match File::open(path) {
-        Err(why) => panic!("couldn't open {}: {}", path.display(), why),
-        Ok(file) => file,
-    };
-
-    if path.extension() == Some(OsStr::new("gz")) {
-        Box::new(BufReader::with_capacity(1024 * 1024, GzDecoder::new(file)))
+    let stream: Box<dyn BufRead> = if filename == STDIN {
+        Box::new(BufReader::with_capacity(1024 * 1024, io::stdin()))
     } else {
+        let file = File::open(filename)?;
         Box::new(BufReader::with_capacity(1024 * 1024, file))
+    };
+    compression::decoder(stream)
+}
+
+/// Turns the paths given on the command line into the log files to read. Directories are
+/// walked recursively, skipping hidden files, and paths that don't exist are treated as
+/// glob patterns like `logs/2024/**/*.gz`. Files come out sorted within each directory
+/// or pattern, and `-` is passed through for standard input.
+pub fn expand_paths(paths: &[String]) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path == STDIN {
+            files.push(path.clone());
+        } else if Path::new(path).exists() {
+            walk(Path::new(path), &mut files)?;
+        } else if path.contains(['*', '?', '[']) {
+            let pattern = glob::glob(path)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", path, e)))?;
+            let start = files.len();
+            for entry in pattern {
+                let entry = entry.map_err(glob::GlobError::into_error)?;
+                walk(&entry, &mut files)?;
+            }
+            if files.len() == start {
+                let message = format!("no files match {}", path);
+                return Err(Error::new(ErrorKind::NotFound, message));
+            }
+        } else {
+            let message = format!("{}: no such file or directory", path);
+            return Err(Error::new(ErrorKind::NotFound, message));
+        }
+    }
+    Ok(files)
+}
+
+fn walk(path: &Path, files: &mut Vec<String>) -> Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_string_lossy().into_owned());
+        return Ok(());
+    }
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_>>()?;
+    entries.sort();
+    for entry in entries {
+        let hidden = entry
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if !hidden {
+            walk(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_paths() {
+        let dir = std::env::temp_dir().join(format!("kirby-expand-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("2024/04")).unwrap();
+        for file in [
+            "2024/04/b.log.gz",
+            "2024/04/a.log.gz",
+            "2024/c.log",
+            "2024/.hidden",
+        ] {
+            File::create(dir.join(file)).unwrap();
+        }
+        let root = dir.to_string_lossy().into_owned();
+        let expand = |paths: &[&str]| {
+            let paths: Vec<String> = paths.iter().map(|p| p.replace("$", &root)).collect();
+            expand_paths(&paths).map(|files| {
+                files
+                    .iter()
+                    .map(|f| f.replacen(&root, "$", 1))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(
+            expand(&["$/2024"]).unwrap(),
+            ["$/2024/04/a.log.gz", "$/2024/04/b.log.gz", "$/2024/c.log"]
+        );
+        assert_eq!(
+            expand(&["$/2024/**/*.gz", "-", "$/2024/c.log"]).unwrap(),
+            [
+                "$/2024/04/a.log.gz",
+                "$/2024/04/b.log.gz",
+                "-",
+                "$/2024/c.log"
+            ]
+        );
+        assert_eq!(expand(&["$/*/*.log"]).unwrap(), ["$/2024/c.log"]);
+        assert!(expand(&["$/2023/*.gz"]).is_err());
+        assert!(expand(&["$/2024/missing.log"]).is_err());
+        assert!(expand(&["$/[.log"]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,679 @@
+//! A small expression language for picking which log lines to process, like
+//! `request_host == "index.rubygems.org" && ua.ci != null`.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use regex::Regex;
+
+use crate::request::{Clickhouse, Request};
+use crate::user_agent::UserAgent;
+use crate::version::Version;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Value<'a> {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(&'a str),
+}
+
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(s: &'a str) -> Self {
+        if s.is_empty() {
+            Value::Null
+        } else {
+            Value::Str(s)
+        }
+    }
+}
+
+impl<'a> From<Option<&'a str>> for Value<'a> {
+    fn from(s: Option<&'a str>) -> Self {
+        s.map_or(Value::Null, Value::from)
+    }
+}
+
+impl<'a> From<&'a Option<Cow<'a, str>>> for Value<'a> {
+    fn from(s: &'a Option<Cow<'a, str>>) -> Self {
+        Value::from(s.as_deref())
+    }
+}
+
+impl Value<'_> {
+    fn number(n: Option<u64>) -> Self {
+        n.map_or(Value::Null, |n| Value::Number(n as f64))
+    }
+
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (*self, *other) {
+            (Value::Null, Value::Null) => Some(Ordering::Equal),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(&b)),
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(&b),
+            (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+            (Value::Number(a), Value::Str(b)) => a.partial_cmp(&b.parse().ok()?),
+            (Value::Str(a), Value::Number(b)) => a.parse::<f64>().ok()?.partial_cmp(&b),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed log line that filters can read fields from.
+pub(crate) trait Record {
+    /// The names of the fields filters can compare
+    const FIELDS: &'static [&'static str];
+
+    fn field(&self, name: &str) -> Value<'_>;
+}
+
+impl Record for Request<'_> {
+    const FIELDS: &'static [&'static str] = &[
+        "timestamp",
+        "client_ip",
+        "client_continent",
+        "client_country",
+        "request",
+        "request_host",
+        "request_path",
+        "request_query",
+        "user_agent",
+        "tls_cipher",
+        "response_status",
+        "response_bytes",
+        "response_cache",
+        "cache_state",
+        "time_elapsed",
+        "server_region",
+        "server_datacenter",
+    ];
+
+    fn field(&self, name: &str) -> Value<'_> {
+        match name {
+            "timestamp" => Value::from(self.shared.timestamp.as_ref()),
+            "client_ip" => Value::from(self.client_ip.as_ref()),
+            "client_continent" => Value::from(&self.client_continent),
+            "client_country" => Value::from(&self.client_country),
+            "request" => Value::from(&self.method),
+            "request_host" => Value::from(&self.request_host),
+            "request_path" => Value::from(self.shared.request_path.as_ref()),
+            "request_query" => Value::from(self.shared.request_query.as_ref()),
+            "user_agent" => Value::from(self.shared.user_agent.as_ref()),
+            "tls_cipher" => Value::from(self.shared.tls_cipher.as_ref()),
+            "response_status" => Value::number(self.response_status.as_ref().map(|s| s.0.into())),
+            "response_bytes" => Value::number(self.response_bytes),
+            "response_cache" => Value::from(&self.response_cache),
+            "cache_state" => Value::from(&self.cache_state),
+            "time_elapsed" => Value::number(self.time_elapsed),
+            "server_region" => Value::from(&self.server_region),
+            "server_datacenter" => Value::from(&self.server_datacenter),
+            _ => Value::Null,
+        }
+    }
+}
+
+impl Record for Clickhouse<'_> {
+    const FIELDS: &'static [&'static str] = &[
+        "timestamp",
+        "time_elapsed",
+        "client_continent",
+        "client_country",
+        "client_region",
+        "client_city",
+        "client_latitude",
+        "client_longitude",
+        "client_timezone",
+        "client_connection",
+        "request",
+        "request_host",
+        "request_path",
+        "request_query",
+        "request_bytes",
+        "user_agent",
+        "http2",
+        "tls",
+        "tls_version",
+        "tls_cipher",
+        "response_status",
+        "response_text",
+        "response_bytes",
+        "response_cache",
+        "cache_state",
+        "cache_lastuse",
+        "cache_hits",
+        "server_region",
+        "server_datacenter",
+        "gem",
+        "version",
+        "platform",
+    ];
+
+    fn field(&self, name: &str) -> Value<'_> {
+        match name {
+            "timestamp" => Value::from(self.shared.timestamp.as_ref()),
+            "time_elapsed" => Value::Number(self.time_elapsed.into()),
+            "client_continent" => Value::from(self.client_continent.as_ref()),
+            "client_country" => Value::from(self.client_country.as_ref()),
+            "client_region" => Value::from(self.client_region.as_ref()),
+            "client_city" => Value::from(self.client_city.as_ref()),
+            "client_latitude" => Value::from(&self.client_latitude),
+            "client_longitude" => Value::from(&self.client_longitude),
+            "client_timezone" => Value::from(self.client_timezone.as_ref()),
+            "client_connection" => Value::from(self.client_connection.as_ref()),
+            "request" => Value::from(self.request.as_ref()),
+            "request_host" => Value::from(self.request_host.as_ref()),
+            "request_path" => Value::from(self.shared.request_path.as_ref()),
+            "request_query" => Value::from(self.shared.request_query.as_ref()),
+            "request_bytes" => Value::Number(self.request_bytes.into()),
+            "user_agent" => Value::from(self.shared.user_agent.as_ref()),
+            "http2" => Value::Bool(self.http2),
+            "tls" => self.tls.map_or(Value::Null, Value::Bool),
+            "tls_version" => Value::from(self.tls_version.as_ref()),
+            "tls_cipher" => Value::from(self.shared.tls_cipher.as_ref()),
+            "response_status" => Value::Number(self.response_status.0.into()),
+            "response_text" => Value::from(self.response_text.as_ref()),
+            "response_bytes" => Value::Number(self.response_bytes.into()),
+            "response_cache" => Value::from(self.response_cache.as_ref()),
+            "cache_state" => Value::from(self.cache_state.as_ref()),
+            "cache_lastuse" => Value::Number(self.cache_lastuse.into()),
+            "cache_hits" => Value::Number(self.cache_hits.into()),
+            "server_region" => Value::from(self.server_region.as_ref()),
+            "server_datacenter" => Value::from(self.server_datacenter.as_ref()),
+            "gem" => Value::from(&self.gem),
+            "version" => Value::from(&self.version),
+            "platform" => Value::from(&self.platform),
+            _ => Value::Null,
+        }
+    }
+}
+
+const USER_AGENT_FIELDS: &[&str] = &[
+    "agent_name",
+    "agent_version",
+    "bundler",
+    "rubygems",
+    "ruby",
+    "platform",
+    "command",
+    "options",
+    "jruby",
+    "truffleruby",
+    "ci",
+    "uid",
+    "gemstash",
+];
+
+// Fields whose values are versions, compared as `Gem::Version`s. The ClickHouse `version`
+// is the gem's.
+const USER_AGENT_VERSIONS: &[&str] = &[
+    "agent_version",
+    "bundler",
+    "rubygems",
+    "ruby",
+    "jruby",
+    "truffleruby",
+    "gemstash",
+];
+const VERSIONS: &[&str] = &["version"];
+
+fn user_agent_field<'a>(ua: &UserAgent<'a>, name: &str) -> Option<&'a str> {
+    match name {
+        "agent_name" => ua.agent_name,
+        "agent_version" => ua.agent_version,
+        "bundler" => ua.bundler,
+        "rubygems" => ua.rubygems,
+        "ruby" => ua.ruby,
+        "platform" => ua.platform,
+        "command" => ua.command,
+        "options" => ua.options,
+        "jruby" => ua.jruby,
+        "truffleruby" => ua.truffleruby,
+        "ci" => ua.ci,
+        "uid" => ua.uid,
+        "gemstash" => ua.gemstash,
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operand {
+    Field(&'static str),
+    UserAgent(&'static str),
+}
+
+impl Operand {
+    fn is_version(&self) -> bool {
+        match *self {
+            Operand::Field(name) => VERSIONS.contains(&name),
+            Operand::UserAgent(name) => USER_AGENT_VERSIONS.contains(&name),
+        }
+    }
+
+    fn value<'a, R: Record>(&self, record: &'a R, ua: Option<&UserAgent<'a>>) -> Value<'a> {
+        match *self {
+            Operand::Field(name) => record.field(name),
+            Operand::UserAgent(name) => Value::from(ua.and_then(|ua| user_agent_field(ua, name))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Literal {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Version(Version),
+}
+
+impl Literal {
+    fn compare(&self, value: Value) -> Option<Ordering> {
+        match self {
+            Literal::Null => value.compare(&Value::Null),
+            Literal::Bool(b) => value.compare(&Value::Bool(*b)),
+            Literal::Number(n) => value.compare(&Value::Number(*n)),
+            Literal::Str(s) => value.compare(&Value::from(s.as_str())),
+            Literal::Version(version) => match value {
+                Value::Str(s) => Some(s.parse::<Version>().ok()?.cmp(version)),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Op, Literal),
+    Match(Operand, Regex),
+}
+
+impl Expr {
+    fn eval<'a, R: Record>(&self, record: &'a R, ua: Option<&UserAgent<'a>>) -> bool {
+        match self {
+            Expr::And(left, right) => left.eval(record, ua) && right.eval(record, ua),
+            Expr::Or(left, right) => left.eval(record, ua) || right.eval(record, ua),
+            Expr::Not(expr) => !expr.eval(record, ua),
+            Expr::Compare(operand, op, literal) => {
+                let ordering = literal.compare(operand.value(record, ua));
+                match op {
+                    Op::Eq => ordering == Some(Ordering::Equal),
+                    Op::Ne => ordering != Some(Ordering::Equal),
+                    Op::Lt => ordering == Some(Ordering::Less),
+                    Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                    Op::Gt => ordering == Some(Ordering::Greater),
+                    Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+                }
+            }
+            Expr::Match(operand, regex) => {
+                matches!(operand.value(record, ua), Value::Str(s) if regex.is_match(s))
+            }
+        }
+    }
+
+    fn uses_user_agent(&self) -> bool {
+        match self {
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                left.uses_user_agent() || right.uses_user_agent()
+            }
+            Expr::Not(expr) => expr.uses_user_agent(),
+            Expr::Compare(operand, _, _) | Expr::Match(operand, _) => {
+                matches!(operand, Operand::UserAgent(_))
+            }
+        }
+    }
+}
+
+/// A parsed filter expression, which lines have to match to be counted or converted.
+#[derive(Debug)]
+pub struct Filter {
+    expr: Expr,
+    user_agent: bool,
+}
+
+impl Filter {
+    /// Parses a filter over the fields that are read from each line to count stats.
+    pub fn for_stats(expr: &str) -> Result<Filter, String> {
+        Filter::parse(expr, Request::FIELDS)
+    }
+
+    /// Parses a filter over the fields of each row written for ClickHouse.
+    pub fn for_clickhouse(expr: &str) -> Result<Filter, String> {
+        Filter::parse(expr, Clickhouse::FIELDS)
+    }
+
+    fn parse(expr: &str, fields: &'static [&'static str]) -> Result<Filter, String> {
+        let mut parser = Parser {
+            tokens: tokenize(expr)?,
+            pos: 0,
+            end: expr.len(),
+            fields,
+        };
+        let expr = parser.or()?;
+        if let Some((at, token)) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {} at column {}", token, at + 1));
+        }
+        let user_agent = expr.uses_user_agent();
+        Ok(Filter { expr, user_agent })
+    }
+
+    /// Whether the filter reads `ua.` fields, so the user agent has to be parsed first.
+    pub(crate) fn uses_user_agent(&self) -> bool {
+        self.user_agent
+    }
+
+    pub(crate) fn matches<'a, R: Record>(&self, record: &'a R, ua: Option<&UserAgent<'a>>) -> bool {
+        self.expr.eval(record, ua)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    // Kept as written, since a version like `2.10` isn't the number `2.1`
+    Number(String),
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Str(s) => write!(f, "{:?}", s),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+const SYMBOLS: &[&str] = &[
+    "==", "!=", "=~", "!~", "<=", ">=", "&&", "||", "<", ">", "!", "(", ")",
+];
+
+fn tokenize(expr: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while let Some(c) = expr[pos..].chars().next() {
+        let start = pos;
+        let rest = &expr[pos..];
+        if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        }
+
+        let token = if c == '"' || c == '\'' {
+            let mut string = String::new();
+            let mut chars = rest.char_indices().skip(1);
+            loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => string.push(escaped),
+                        None => return Err(format!("unterminated string at column {}", start + 1)),
+                    },
+                    Some((i, quote)) if quote == c => {
+                        pos += i + 1;
+                        break;
+                    }
+                    Some((_, c)) => string.push(c),
+                    None => return Err(format!("unterminated string at column {}", start + 1)),
+                }
+            }
+            Token::Str(string)
+        } else if c.is_ascii_digit()
+            || (c == '-' && rest[1..].starts_with(|c: char| c.is_ascii_digit()))
+        {
+            let len = 1 + rest[1..]
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len() - 1);
+            pos += len;
+            Token::Number(rest[..len].to_string())
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            pos += len;
+            Token::Ident(rest[..len].to_string())
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            pos += symbol.len();
+            Token::Symbol(symbol)
+        } else {
+            return Err(format!("unexpected {:?} at column {}", c, start + 1));
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+    fields: &'static [&'static str],
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(_, token)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn column(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |&(at, _)| at) + 1
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found =
+            matches!(self.tokens.get(self.pos), Some((_, Token::Symbol(s))) if *s == symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while self.eat("&&") {
+            left = Expr::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err(format!("expected ) at column {}", self.column()));
+            }
+            Ok(expr)
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let column = self.column();
+        let operand = match self.next() {
+            Some(Token::Ident(name)) => self.operand(&name)?,
+            _ => return Err(format!("expected a field name at column {}", column)),
+        };
+
+        let column = self.column();
+        let op = match self.next() {
+            Some(Token::Symbol(symbol @ ("=~" | "!~"))) => {
+                let column = self.column();
+                let Some(Token::Str(pattern)) = self.next() else {
+                    return Err(format!(
+                        "expected a regular expression at column {}",
+                        column
+                    ));
+                };
+                let regex = Regex::new(&pattern).map_err(|e| e.to_string())?;
+                let expr = Expr::Match(operand, regex);
+                return Ok(match symbol {
+                    "!~" => Expr::Not(Box::new(expr)),
+                    _ => expr,
+                });
+            }
+            Some(Token::Symbol("==")) => Op::Eq,
+            Some(Token::Symbol("!=")) => Op::Ne,
+            Some(Token::Symbol("<")) => Op::Lt,
+            Some(Token::Symbol("<=")) => Op::Le,
+            Some(Token::Symbol(">")) => Op::Gt,
+            Some(Token::Symbol(">=")) => Op::Ge,
+            _ => return Err(format!("expected a comparison at column {}", column)),
+        };
+
+        let column = self.column();
+        let literal = match self.next() {
+            Some(Token::Str(s) | Token::Number(s)) if operand.is_version() => {
+                let version = s
+                    .parse()
+                    .map_err(|_| format!("invalid version {:?} at column {}", s, column))?;
+                Literal::Version(version)
+            }
+            Some(Token::Str(s)) => Literal::Str(s),
+            Some(Token::Number(n)) => {
+                let number = n
+                    .parse()
+                    .map_err(|_| format!("invalid number {:?} at column {}", n, column))?;
+                Literal::Number(number)
+            }
+            Some(Token::Ident(name)) if name == "null" => Literal::Null,
+            Some(Token::Ident(name)) if name == "true" => Literal::Bool(true),
+            Some(Token::Ident(name)) if name == "false" => Literal::Bool(false),
+            _ => return Err(format!("expected a value at column {}", column)),
+        };
+        Ok(Expr::Compare(operand, op, literal))
+    }
+
+    fn operand(&self, name: &str) -> Result<Operand, String> {
+        let (fields, operand): (_, fn(&'static str) -> Operand) = match name.strip_prefix("ua.") {
+            Some(_) => (USER_AGENT_FIELDS, Operand::UserAgent),
+            None => (self.fields, Operand::Field),
+        };
+        let field = name.strip_prefix("ua.").unwrap_or(name);
+        match fields.iter().find(|&&f| f == field) {
+            Some(field) => Ok(operand(field)),
+            None => Err(format!(
+                "unknown field {:?}, expected one of {}, or ua. and one of {}",
+                name,
+                self.fields.join(", "),
+                USER_AGENT_FIELDS.join(", ")
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_agent::ParseCtx;
+
+    fn matches(filter: &str, line: &str) -> bool {
+        let filter = Filter::for_stats(filter).unwrap();
+        let request = Request::from_line(line).unwrap();
+        let ctx = ParseCtx::new();
+        let ua = ctx.parse(&mut ctx.capture_locations(), &request.shared.user_agent);
+        filter.matches(&request, ua.as_ref())
+    }
+
+    #[test]
+    fn test_filter() {
+        let line = r#"{"timestamp":"2018-04-16 04:59:59","time_elapsed":1,"client_ip":"139.130.87.202","client_country":"Australia","request":"GET","request_host":"rubygems.org","request_path":"/versions","request_query":"","user_agent":"bundler/1.16.1 rubygems/2.6.11 ruby/2.4.1 (x86_64-pc-linux-gnu) command/install options/no_install ci/travis 59dbf8e99fa09c0a","tls_cipher":"ECDHE-RSA-AES128-GCM-SHA256","response_status":"200","response_bytes":1855}"#;
+
+        for filter in [
+            r#"request_host == "rubygems.org" && client_country == "Australia""#,
+            r#"client_country == 'Germany' || request_path =~ "^/versions$""#,
+            "ua.ci != null && ua.ruby >= '2.4' && ua.gemstash == null",
+            "response_status == 200 && response_status < 300 && response_bytes > 1000",
+            r#"response_status == "200" && time_elapsed <= 1 && request_query == null"#,
+            r#"!(client_country == "Germany") && request_path !~ "^/gems/""#,
+            r#"server_region == null && (request == "HEAD" || request == "GET")"#,
+            "ua.rubygems > 2.6.9 && ua.ruby >= 2.4 && ua.ruby < '2.10' && ua.bundler == 1.16.1.0",
+        ] {
+            assert!(matches(filter, line), "{filter}");
+        }
+        for filter in [
+            r#"client_country == "Germany""#,
+            "ua.ci == null",
+            "response_status >= 400",
+            r#"server_region =~ "." || client_country < "America""#,
+            "response_status == true",
+            "ua.rubygems < '2.6.9'",
+            "ua.ruby >= 2.10",
+        ] {
+            assert!(!matches(filter, line), "{filter}");
+        }
+    }
+
+    #[test]
+    fn test_filter_errors() {
+        let error = |filter| Filter::for_stats(filter).unwrap_err();
+        assert_eq!(
+            error(r#"client_city == "x""#).split(',').next().unwrap(),
+            "unknown field \"client_city\""
+        );
+        assert!(error("ua.rubie == null").starts_with("unknown field \"ua.rubie\""));
+        assert_eq!(
+            error("client_country = 'Germany'"),
+            "unexpected '=' at column 16"
+        );
+        assert_eq!(error("client_country =="), "expected a value at column 18");
+        assert_eq!(error("(ua.ci != null"), "expected ) at column 15");
+        assert_eq!(
+            error("ua.ci != null ua.ruby"),
+            "unexpected ua.ruby at column 15"
+        );
+        assert_eq!(
+            error("request_path =~ '('").lines().next().unwrap(),
+            "regex parse error:"
+        );
+        assert_eq!(
+            error("'Germany' == client_country"),
+            "expected a field name at column 1"
+        );
+        assert_eq!(
+            error("response_bytes > 1.0.0"),
+            "invalid number \"1.0.0\" at column 18"
+        );
+        assert_eq!(
+            error("ua.ruby >= 'three'"),
+            "invalid version \"three\" at column 12"
+        );
+
+        assert!(Filter::for_clickhouse(r#"client_city == "x" && gem == "rack""#).is_ok());
+        assert!(
+            Filter::for_stats("ua.ci != null")
+                .unwrap()
+                .uses_user_agent()
+        );
+        assert!(
+            !Filter::for_stats("request == 'GET'")
+                .unwrap()
+                .uses_user_agent()
+        );
+    }
+}
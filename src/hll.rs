@@ -0,0 +1,334 @@
+//! HyperLogLog sketches for approximate unique counts, which merge into the sketch of the
+//! union so per-file uniques can be combined.
+
+use std::fmt;
+use std::hash::Hasher;
+
+use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use siphasher::sip::SipHasher13;
+
+pub const PRECISION: u8 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+// From the HyperLogLog paper, the raw estimate below which it is biased, and linear
+// counting is used instead. HyperLogLog++ uses a lower threshold, but only together with
+// its empirical bias correction, which this doesn't implement.
+const LINEAR_COUNTING_THRESHOLD: f64 = 2.5 * REGISTERS as f64;
+// The rank of a hash is one more than the leading zeros of the bits after the register,
+// so it's at least 1, and at most 1 more than the number of those bits.
+const MAX_RANK: u8 = 64 - PRECISION + 1;
+// A sparse entry takes three bytes when serialized, a dense register takes one.
+const SPARSE_LIMIT: usize = REGISTERS / 3;
+
+/// Hashes bytes into the value a sketch expects. The hash is stable across runs and
+/// machines, which is what lets sketches from different files be merged.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hasher = SipHasher13::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HyperLogLog {
+    /// (register, rank) pairs sorted by register, for registers that aren't zero
+    Sparse(Vec<(u16, u8)>),
+    Dense(Box<[u8]>),
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog::Sparse(Vec::new())
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert(&mut self, hash: u64) {
+        let register = (hash >> (64 - PRECISION)) as u16;
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        self.set(register, rank);
+    }
+
+    fn set(&mut self, register: u16, rank: u8) {
+        match self {
+            HyperLogLog::Sparse(entries) => {
+                match entries.binary_search_by_key(&register, |&(r, _)| r) {
+                    Ok(i) => entries[i].1 = entries[i].1.max(rank),
+                    Err(i) => entries.insert(i, (register, rank)),
+                }
+                if entries.len() > SPARSE_LIMIT {
+                    self.densify();
+                }
+            }
+            HyperLogLog::Dense(registers) => {
+                let r = &mut registers[register as usize];
+                *r = (*r).max(rank);
+            }
+        }
+    }
+
+    fn densify(&mut self) {
+        if let HyperLogLog::Sparse(entries) = self {
+            let mut registers = vec![0; REGISTERS].into_boxed_slice();
+            for &(register, rank) in entries.iter() {
+                registers[register as usize] = rank;
+            }
+            *self = HyperLogLog::Dense(registers);
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        match other {
+            HyperLogLog::Sparse(entries) => {
+                for &(register, rank) in entries {
+                    self.set(register, rank);
+                }
+            }
+            HyperLogLog::Dense(other_registers) => {
+                self.densify();
+                if let HyperLogLog::Dense(registers) = self {
+                    for (r, &o) in registers.iter_mut().zip(other_registers.iter()) {
+                        *r = (*r).max(o);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn estimate(&self) -> usize {
+        let m = REGISTERS as f64;
+        let (sum, zeros) = match self {
+            HyperLogLog::Sparse(entries) => {
+                let zeros = REGISTERS - entries.len();
+                let set: f64 = entries
+                    .iter()
+                    .map(|&(_, rank)| 2f64.powi(-(rank as i32)))
+                    .sum();
+                (set + zeros as f64, zeros)
+            }
+            HyperLogLog::Dense(registers) => {
+                let zeros = registers.iter().filter(|&&r| r == 0).count();
+                let sum = registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+                (sum, zeros)
+            }
+        };
+
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let raw = alpha * m * m / sum;
+        if zeros > 0 && raw <= LINEAR_COUNTING_THRESHOLD {
+            return (m * (m / zeros as f64).ln()).round() as usize;
+        }
+        raw.round() as usize
+    }
+}
+
+fn to_hex(bytes: impl Iterator<Item = u8>) -> String {
+    let mut s = String::new();
+    for b in bytes {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+fn from_hex<E: de::Error>(s: &str) -> Result<Vec<u8>, E> {
+    if !s.len().is_multiple_of(2) {
+        return Err(E::custom("odd length hex string"));
+    }
+    let digit = |b: u8| {
+        (b as char)
+            .to_digit(16)
+            .ok_or_else(|| E::custom(format!("invalid hex digit {:?}", b as char)))
+    };
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok((digit(pair[0])? * 16 + digit(pair[1])?) as u8))
+        .collect()
+}
+
+fn check_rank<E: de::Error>(rank: u8) -> Result<u8, E> {
+    if rank > MAX_RANK {
+        return Err(E::custom(format!("sketch rank {rank} is out of range")));
+    }
+    Ok(rank)
+}
+
+impl Serialize for HyperLogLog {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("HyperLogLog", 2)?;
+        s.serialize_field("p", &PRECISION)?;
+        match self {
+            HyperLogLog::Sparse(entries) => {
+                let bytes = entries.iter().flat_map(|&(register, rank)| {
+                    let [hi, lo] = register.to_be_bytes();
+                    [hi, lo, rank]
+                });
+                s.serialize_field("sparse", &to_hex(bytes))?;
+            }
+            HyperLogLog::Dense(registers) => {
+                s.serialize_field("dense", &to_hex(registers.iter().copied()))?;
+            }
+        }
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for HyperLogLog {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SketchVisitor;
+
+        impl<'de> Visitor<'de> for SketchVisitor {
+            type Value = HyperLogLog;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt.write_str("a HyperLogLog sketch")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut sketch = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "p" => {
+                            let p: u8 = map.next_value()?;
+                            if p != PRECISION {
+                                return Err(de::Error::custom(format!(
+                                    "sketch precision {p} doesn't match {PRECISION}"
+                                )));
+                            }
+                        }
+                        "sparse" => {
+                            let bytes = from_hex(&map.next_value::<String>()?)?;
+                            if !bytes.len().is_multiple_of(3) {
+                                return Err(de::Error::custom("truncated sparse sketch"));
+                            }
+                            let mut hll = HyperLogLog::default();
+                            for entry in bytes.chunks(3) {
+                                let register = u16::from_be_bytes([entry[0], entry[1]]);
+                                if register as usize >= REGISTERS {
+                                    return Err(de::Error::custom("sketch register out of range"));
+                                }
+                                // Only registers that are set are sparse entries
+                                if entry[2] == 0 {
+                                    return Err(de::Error::custom("sparse sketch rank is 0"));
+                                }
+                                hll.set(register, check_rank(entry[2])?);
+                            }
+                            sketch = Some(hll);
+                        }
+                        "dense" => {
+                            let bytes = from_hex(&map.next_value::<String>()?)?;
+                            if bytes.len() != REGISTERS {
+                                return Err(de::Error::invalid_length(bytes.len(), &self));
+                            }
+                            for &rank in &bytes {
+                                check_rank(rank)?;
+                            }
+                            sketch = Some(HyperLogLog::Dense(bytes.into_boxed_slice()));
+                        }
+                        _ => {
+                            map.next_value::<de::IgnoredAny>()?;
+                        }
+                    }
+                }
+                sketch.ok_or_else(|| de::Error::missing_field("sparse"))
+            }
+        }
+
+        deserializer.deserialize_struct("HyperLogLog", &["p", "sparse", "dense"], SketchVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch_of(range: std::ops::Range<u64>) -> HyperLogLog {
+        let mut hll = HyperLogLog::default();
+        for i in range {
+            hll.insert(hash(&i.to_le_bytes()));
+        }
+        hll
+    }
+
+    #[test]
+    fn test_small_counts_are_exact() {
+        for n in [0, 1, 2, 10, 30] {
+            let hll = sketch_of(0..n);
+            assert_eq!(hll.estimate(), n as usize);
+        }
+        let mut hll = sketch_of(0..10);
+        hll.merge(&sketch_of(0..10));
+        assert_eq!(hll.estimate(), 10);
+    }
+
+    #[test]
+    fn test_estimate_within_error_bound() {
+        // Around the switch from linear counting to the raw estimate is where a bad
+        // threshold shows up, so check a few different sets of users there
+        for n in [1_000, 3_000, 3_500, 5_000, 8_000, 10_000, 20_000, 250_000] {
+            for seed in 0..4 {
+                let start = seed * 1_000_000;
+                let estimate = sketch_of(start..start + n).estimate() as f64;
+                let error = (estimate - n as f64).abs() / n as f64;
+                assert!(
+                    error < 0.05,
+                    "estimate {} for {} is off by {:.3}",
+                    estimate,
+                    n,
+                    error
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_estimate_increases_with_cardinality() {
+        let estimates: Vec<usize> = (1..=40).map(|i| sketch_of(0..i * 250).estimate()).collect();
+        for pair in estimates.windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", estimates);
+        }
+    }
+
+    #[test]
+    fn test_merge_is_union() {
+        let mut left = sketch_of(0..60_000);
+        left.merge(&sketch_of(40_000..100_000));
+        assert_eq!(left, sketch_of(0..100_000));
+
+        let mut sparse = sketch_of(0..50);
+        sparse.merge(&sketch_of(25..75));
+        assert_eq!(sparse, sketch_of(0..75));
+    }
+
+    #[test]
+    fn test_serialize_roundtrip() {
+        for hll in [sketch_of(0..3), sketch_of(0..100_000)] {
+            let json = serde_json::to_string(&hll).unwrap();
+            let back: HyperLogLog = serde_json::from_str(&json).unwrap();
+            assert_eq!(hll, back);
+        }
+        let json = serde_json::to_string(&sketch_of(0..2)).unwrap();
+        assert!(json.starts_with(r#"{"p":12,"sparse":""#), "{}", json);
+    }
+
+    #[test]
+    fn test_invalid_sketches_are_errors() {
+        let dense = |last: &str| format!("{}{}", "00".repeat(REGISTERS - 1), last);
+        for (sketch, error) in [
+            ("aé0", "invalid hex digit"),
+            ("0g0000", "invalid hex digit"),
+            ("000000", "rank is 0"),
+            ("000036", "rank 54 is out of range"),
+            ("100001", "register out of range"),
+        ] {
+            let json = format!(r#"{{"p":12,"sparse":"{}"}}"#, sketch);
+            let e = serde_json::from_str::<HyperLogLog>(&json).unwrap_err();
+            assert!(e.to_string().contains(error), "{sketch}: {e}");
+        }
+
+        let json = format!(r#"{{"p":12,"dense":"{}"}}"#, dense("36"));
+        let e = serde_json::from_str::<HyperLogLog>(&json).unwrap_err();
+        assert!(e.to_string().contains("rank 54 is out of range"), "{e}");
+        let json = format!(r#"{{"p":12,"dense":"{}"}}"#, dense("35"));
+        assert!(serde_json::from_str::<HyperLogLog>(&json).is_ok());
+    }
+}
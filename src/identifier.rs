@@ -0,0 +1,242 @@
+use std::fmt;
+use std::hash::Hasher;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use siphasher::sip::SipHasher13;
+use siphasher::sip128::{Hasher128, SipHasher13 as SipHasher128};
+
+use crate::hll;
+
+/// Who a request came from, for counting uniques.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum UserIdentifier {
+    Ip(IpAddr),
+    Hashed(u64),
+}
+
+impl UserIdentifier {
+    /// The hash fed into HyperLogLog sketches.
+    pub fn sketch_hash(&self) -> u64 {
+        match self {
+            UserIdentifier::Ip(IpAddr::V4(ip)) => hll::hash(&ip.octets()),
+            UserIdentifier::Ip(IpAddr::V6(ip)) => hll::hash(&ip.octets()),
+            UserIdentifier::Hashed(hash) => hll::hash(&hash.to_le_bytes()),
+        }
+    }
+}
+
+/// How client IPs are turned into the identifiers that uniques are counted by.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum IdentifierStrategy {
+    /// The IP address as is.
+    #[default]
+    Raw,
+    /// The IPv4 /24 or IPv6 /64 network the address is in, so that IPv6 privacy addresses
+    /// count as one user, and no full address is kept.
+    Truncate,
+    /// A keyed hash of the IP address, with a key derived from a secret and the UTC day of
+    /// the request. The same address gets an unrelated identifier every day, so uniques in
+    /// buckets longer than a day count user-days, and can't be combined across days.
+    Hash { key: (u64, u64) },
+}
+
+impl IdentifierStrategy {
+    /// Builds a strategy from its name: `raw`, `truncate`, or `hash`. Hashing needs a
+    /// secret, which should come from somewhere other than the command line.
+    pub fn new(name: &str, secret: Option<&str>) -> Result<Self, String> {
+        match (name, secret) {
+            ("raw", _) => Ok(IdentifierStrategy::Raw),
+            ("truncate", _) => Ok(IdentifierStrategy::Truncate),
+            ("hash", Some(secret)) if !secret.is_empty() => {
+                let mut hasher = SipHasher128::new();
+                hasher.write(secret.as_bytes());
+                let key = hasher.finish128();
+                Ok(IdentifierStrategy::Hash {
+                    key: (key.h1, key.h2),
+                })
+            }
+            ("hash", _) => Err("hashing identifiers needs a secret".to_string()),
+            _ => Err(format!("unknown identifier strategy {:?}", name)),
+        }
+    }
+
+    /// Identifies the user behind a request from `ip` at `timestamp`.
+    pub fn identify(&self, ip: IpAddr, timestamp: &str) -> UserIdentifier {
+        match self {
+            IdentifierStrategy::Raw => UserIdentifier::Ip(ip),
+            IdentifierStrategy::Truncate => UserIdentifier::Ip(truncate(ip)),
+            IdentifierStrategy::Hash { .. } => {
+                let mut hasher = self.hasher(timestamp);
+                write_ip(&mut hasher, ip);
+                UserIdentifier::Hashed(hasher.finish())
+            }
+        }
+    }
+
+    /// A hasher for building identifiers out of more than an IP. Keyed with the day's
+    /// salt when hashing, so those identifiers can't be linked across days either.
+    fn hasher(&self, timestamp: &str) -> SipHasher13 {
+        match self {
+            IdentifierStrategy::Raw | IdentifierStrategy::Truncate => SipHasher13::new(),
+            IdentifierStrategy::Hash { key } => {
+                let day = timestamp.get(..10).unwrap_or_default();
+                let mut salt = SipHasher128::new_with_keys(key.0, key.1);
+                salt.write(day.as_bytes());
+                let salt = salt.finish128();
+                SipHasher13::new_with_keys(salt.h1, salt.h2)
+            }
+        }
+    }
+}
+
+/// What counts as one user when counting uniques.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UniqueBy {
+    /// The client IP, after the identifier strategy is applied.
+    #[default]
+    Ip,
+    /// The client IP and the whole user agent, so that different machines behind one
+    /// NAT usually count separately.
+    IpUserAgent,
+    /// The identifier Bundler sends with each command, falling back to the client IP for
+    /// other clients. This counts Bundler commands rather than machines.
+    BundlerUid,
+}
+
+impl UniqueBy {
+    /// Identifies the user behind a request. `uid` is the Bundler identifier from the
+    /// user agent, if there is one.
+    pub fn identify(
+        &self,
+        strategy: &IdentifierStrategy,
+        ip: IpAddr,
+        user_agent: &str,
+        uid: Option<&str>,
+        timestamp: &str,
+    ) -> UserIdentifier {
+        match (self, uid) {
+            (UniqueBy::BundlerUid, Some(uid)) => {
+                let mut hasher = strategy.hasher(timestamp);
+                hasher.write(b"uid");
+                hasher.write(uid.as_bytes());
+                UserIdentifier::Hashed(hasher.finish())
+            }
+            (UniqueBy::IpUserAgent, _) => {
+                let ip = match strategy {
+                    IdentifierStrategy::Truncate => truncate(ip),
+                    _ => ip,
+                };
+                let mut hasher = strategy.hasher(timestamp);
+                write_ip(&mut hasher, ip);
+                // 0xff never appears in UTF-8, so this can't be confused with the user agent
+                hasher.write_u8(0xff);
+                hasher.write(user_agent.as_bytes());
+                UserIdentifier::Hashed(hasher.finish())
+            }
+            _ => strategy.identify(ip, timestamp),
+        }
+    }
+}
+
+impl FromStr for UniqueBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(UniqueBy::Ip),
+            "ip+ua" => Ok(UniqueBy::IpUserAgent),
+            "bundler-uid" => Ok(UniqueBy::BundlerUid),
+            _ => Err(format!("unknown unique key {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for UniqueBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UniqueBy::Ip => "ip",
+            UniqueBy::IpUserAgent => "ip+ua",
+            UniqueBy::BundlerUid => "bundler-uid",
+        })
+    }
+}
+
+fn write_ip(hasher: &mut SipHasher13, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => hasher.write(&ip.octets()),
+        IpAddr::V6(ip) => hasher.write(&ip.octets()),
+    }
+}
+
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            octets[8..].fill(0);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identify() {
+        let v4: IpAddr = "139.130.87.202".parse().unwrap();
+        let v6: IpAddr = "2001:db8:85a3:1:8a2e:370:7334:1".parse().unwrap();
+        let today = "2018-04-16 04:59:59";
+
+        let truncate = IdentifierStrategy::new("truncate", None).unwrap();
+        assert_eq!(
+            truncate.identify(v4, today),
+            UserIdentifier::Ip("139.130.87.0".parse().unwrap())
+        );
+        assert_eq!(
+            truncate.identify(v6, today),
+            UserIdentifier::Ip("2001:db8:85a3:1::".parse().unwrap())
+        );
+
+        let hash = IdentifierStrategy::new("hash", Some("hunter2")).unwrap();
+        let id = hash.identify(v4, today);
+        assert!(matches!(id, UserIdentifier::Hashed(_)));
+        assert_eq!(id, hash.identify(v4, "2018-04-16 23:00:00"));
+        assert_ne!(id, hash.identify(v4, "2018-04-17 00:00:00"));
+        assert_ne!(id, hash.identify(v6, today));
+        let other = IdentifierStrategy::new("hash", Some("hunter3")).unwrap();
+        assert_ne!(id, other.identify(v4, today));
+
+        assert!(IdentifierStrategy::new("hash", None).is_err());
+        assert!(IdentifierStrategy::new("hash", Some("")).is_err());
+        assert!(IdentifierStrategy::new("scramble", None).is_err());
+    }
+
+    #[test]
+    fn test_unique_by() {
+        let ip: IpAddr = "139.130.87.202".parse().unwrap();
+        let today = "2018-04-16 04:59:59";
+        let raw = IdentifierStrategy::Raw;
+        let bundler = "bundler/1.16.1 rubygems/2.6.11 ruby/2.4.1 (x86_64-pc-linux-gnu) command/install 59dbf8e99fa09c0a";
+        let other = "bundler/1.16.1 rubygems/2.6.11 ruby/2.4.1 (x86_64-pc-linux-gnu) command/install e710485d04febb1e";
+
+        let by_ip = UniqueBy::Ip.identify(&raw, ip, bundler, Some("59dbf8e99fa09c0a"), today);
+        assert_eq!(by_ip, UserIdentifier::Ip(ip));
+
+        let uid = |uid| UniqueBy::BundlerUid.identify(&raw, ip, bundler, uid, today);
+        assert_ne!(uid(Some("59dbf8e99fa09c0a")), uid(Some("e710485d04febb1e")));
+        assert_eq!(uid(None), UserIdentifier::Ip(ip));
+
+        let ua = |ua| UniqueBy::IpUserAgent.identify(&raw, ip, ua, None, today);
+        assert_eq!(ua(bundler), ua(bundler));
+        assert_ne!(ua(bundler), ua(other));
+
+        assert_eq!("ip+ua".parse(), Ok(UniqueBy::IpUserAgent));
+        assert!("ua".parse::<UniqueBy>().is_err());
+    }
+}
@@ -6,138 +6,913 @@ extern crate serde_derive;
 #[macro_use]
 extern crate enum_map;
 
+use bucket::Bucket;
+use dead_letter::DeadLetter;
 use enum_map::EnumMap;
+use hll::HyperLogLog;
+use identifier::{IdentifierStrategy, UniqueBy, UserIdentifier};
+use rayon::prelude::*;
+use rules::Rules;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeMap, SerializeStruct, Serializer};
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::collections::HashSet;
+use std::fmt;
 use std::io::*;
-use std::net::IpAddr;
+use std::net::AddrParseError;
+use std::ops::Index;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use symbol::{Symbol, Symbols};
+use traffic::{Response, Traffic};
 use user_agent::ParseCaptureLocations;
+use version::Version;
 
+pub mod bucket;
+mod chunk;
 pub mod clickhouse;
-mod file;
+mod compression;
+pub mod dead_letter;
+pub mod diff;
+pub mod file;
+pub mod filter;
 pub mod full_name_lengths;
+mod hll;
+pub mod identifier;
+pub mod output;
 mod platform;
 mod request;
+pub mod rules;
 pub mod s3;
+mod scan;
+pub mod sqlite;
+pub mod symbol;
+mod traffic;
 mod user_agent;
+pub mod version;
 
-const METADATA_PATHS: [&str; 4] = [
-    "/latest_specs.4.8.gz",
-    "/prerelease_specs.4.8.gz",
-    "/specs.4.8.gz",
-    "/versions",
-];
+static DOWNLOADS: LazyLock<clickhouse::Context<'static>> =
+    LazyLock::new(|| clickhouse::Context::new(&full_name_lengths::FULL_NAMES));
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Enum, Serialize)]
+#[derive(Clone, Copy, Debug, Enum, PartialEq, Serialize)]
 pub enum FieldName {
     tls_cipher,
     server_region,
+    server_datacenter,
+    client_continent,
+    client_country,
     rubygems,
     bundler,
     ruby,
     platform,
     ci,
     gemstash,
+    gem,
+    gem_version,
+    response_status,
+    response_class,
+    cache_state,
+    response_cache,
+    ruby_major,
+    ruby_minor,
+    rubygems_major,
+    rubygems_minor,
+    bundler_major,
+    bundler_minor,
 }
 
-type UserIdentifier = IpAddr;
+impl FieldName {
+    /// Whether this describes the response, and so is counted for every request.
+    pub fn is_response(&self) -> bool {
+        matches!(
+            self,
+            FieldName::response_status
+                | FieldName::response_class
+                | FieldName::cache_state
+                | FieldName::response_cache
+        )
+    }
+
+    /// Whether the values are versions, which are sorted like `Gem::Version`s in the output.
+    pub fn is_version(&self) -> bool {
+        matches!(
+            self,
+            FieldName::rubygems
+                | FieldName::bundler
+                | FieldName::ruby
+                | FieldName::gemstash
+                | FieldName::ruby_major
+                | FieldName::ruby_minor
+                | FieldName::rubygems_major
+                | FieldName::rubygems_minor
+                | FieldName::bundler_major
+                | FieldName::bundler_minor
+        )
+    }
+
+    /// Whether this is only counted with `--gems`, `--responses`, or `--rollups`, and so is
+    /// left out of the output unless that was asked for.
+    pub fn is_optional(&self) -> bool {
+        matches!(self, FieldName::gem | FieldName::gem_version)
+            || self.is_response()
+            || self.is_rollup()
+    }
+
+    /// Whether this is a major or minor release line, only counted with `--rollups`.
+    pub fn is_rollup(&self) -> bool {
+        matches!(
+            self,
+            FieldName::ruby_major
+                | FieldName::ruby_minor
+                | FieldName::rubygems_major
+                | FieldName::rubygems_minor
+                | FieldName::bundler_major
+                | FieldName::bundler_minor
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldName::tls_cipher => "tls_cipher",
+            FieldName::server_region => "server_region",
+            FieldName::server_datacenter => "server_datacenter",
+            FieldName::client_continent => "client_continent",
+            FieldName::client_country => "client_country",
+            FieldName::rubygems => "rubygems",
+            FieldName::bundler => "bundler",
+            FieldName::ruby => "ruby",
+            FieldName::platform => "platform",
+            FieldName::ci => "ci",
+            FieldName::gemstash => "gemstash",
+            FieldName::gem => "gem",
+            FieldName::gem_version => "gem_version",
+            FieldName::response_status => "response_status",
+            FieldName::response_class => "response_class",
+            FieldName::cache_state => "cache_state",
+            FieldName::response_cache => "response_cache",
+            FieldName::ruby_major => "ruby_major",
+            FieldName::ruby_minor => "ruby_minor",
+            FieldName::rubygems_major => "rubygems_major",
+            FieldName::rubygems_minor => "rubygems_minor",
+            FieldName::bundler_major => "bundler_major",
+            FieldName::bundler_minor => "bundler_minor",
+        }
+    }
+}
+
+impl FromStr for FieldName {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "tls_cipher" => Ok(FieldName::tls_cipher),
+            "server_region" => Ok(FieldName::server_region),
+            "server_datacenter" => Ok(FieldName::server_datacenter),
+            "client_continent" => Ok(FieldName::client_continent),
+            "client_country" => Ok(FieldName::client_country),
+            "rubygems" => Ok(FieldName::rubygems),
+            "bundler" => Ok(FieldName::bundler),
+            "ruby" => Ok(FieldName::ruby),
+            "platform" => Ok(FieldName::platform),
+            "ci" => Ok(FieldName::ci),
+            "gemstash" => Ok(FieldName::gemstash),
+            "gem" => Ok(FieldName::gem),
+            "gem_version" => Ok(FieldName::gem_version),
+            "response_status" => Ok(FieldName::response_status),
+            "response_class" => Ok(FieldName::response_class),
+            "cache_state" => Ok(FieldName::cache_state),
+            "response_cache" => Ok(FieldName::response_cache),
+            "ruby_major" => Ok(FieldName::ruby_major),
+            "ruby_minor" => Ok(FieldName::ruby_minor),
+            "rubygems_major" => Ok(FieldName::rubygems_major),
+            "rubygems_minor" => Ok(FieldName::rubygems_minor),
+            "bundler_major" => Ok(FieldName::bundler_major),
+            "bundler_minor" => Ok(FieldName::bundler_minor),
+            _ => Err(format!("unknown field {:?}", s)),
+        }
+    }
+}
+
+/// A composite dimension that counts combinations of values from several fields, such as
+/// `ruby+bundler`. Each combination is keyed by its values joined with `/`, like
+/// `3.3.0/2.5.3`, and is only counted when every field has a value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pivot {
+    name: String,
+    fields: Vec<FieldName>,
+}
+
+impl Pivot {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks that every field of the pivot is counted with these options, since the
+    /// pivot would otherwise always be empty.
+    pub fn check(&self, opts: &Options) -> std::result::Result<(), String> {
+        for field in &self.fields {
+            if field.is_response() && !opts.responses {
+                return Err(format!("pivot {} needs --responses", self.name));
+            }
+            if field.is_rollup() && !opts.rollups {
+                return Err(format!("pivot {} needs --rollups", self.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Pivot {
+    type Err = String;
+
+    /// Parses a list of field names separated by `,` or `+`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let fields = s
+            .split([',', '+'])
+            .map(str::parse)
+            .collect::<std::result::Result<Vec<FieldName>, _>>()?;
+        if fields.len() < 2 {
+            return Err(format!("pivot {:?} needs at least two fields", s));
+        }
+        // Downloads aren't commands, so they're never counted with the other fields
+        if let Some(field) = fields
+            .iter()
+            .find(|f| matches!(f, FieldName::gem | FieldName::gem_version))
+        {
+            return Err(format!("pivot {:?} can't include {}", s, field.as_str()));
+        }
+        let name = fields
+            .iter()
+            .map(FieldName::as_str)
+            .collect::<Vec<_>>()
+            .join("+");
+        Ok(Pivot { name, fields })
+    }
+}
+
+#[derive(Clone, Debug)]
+enum UniqueIndex {
+    Exact(HashSet<UserIdentifier>),
+    Approximate(HyperLogLog),
+}
+
+impl Default for UniqueIndex {
+    fn default() -> Self {
+        UniqueIndex::Exact(HashSet::new())
+    }
+}
 
-#[derive(Default, Serialize)]
+/// Counts every hit for a value, plus the distinct users behind them. Uniques are
+/// tracked with a HyperLogLog sketch (see `hll` for the error bound) that is written out
+/// alongside the counts, unless `Options::exact` asks for a set of every identifier.
+#[derive(Clone, Debug, Default)]
 pub struct ValueUniqueCounter {
     total: usize,
-    unique: usize,
-    #[serde(skip_serializing)]
-    index: HashSet<UserIdentifier>,
+    index: UniqueIndex,
+    traffic: Option<Traffic>,
 }
 
 impl ValueUniqueCounter {
-    fn increment(&mut self, key: UserIdentifier) {
+    fn new(exact: bool) -> Self {
+        let index = if exact {
+            UniqueIndex::Exact(HashSet::new())
+        } else {
+            UniqueIndex::Approximate(HyperLogLog::default())
+        };
+        ValueUniqueCounter {
+            total: 0,
+            index,
+            traffic: None,
+        }
+    }
+
+    pub fn unique(&self) -> usize {
+        match &self.index {
+            UniqueIndex::Exact(index) => index.len(),
+            UniqueIndex::Approximate(sketch) => sketch.estimate(),
+        }
+    }
+
+    fn increment(&mut self, hit: Hit) {
         self.total += 1;
-        if self.index.insert(key) {
-            self.unique += 1;
+        match &mut self.index {
+            UniqueIndex::Exact(index) => {
+                index.insert(hit.user);
+            }
+            UniqueIndex::Approximate(sketch) => sketch.insert(hit.user.sketch_hash()),
+        }
+        if let Some(response) = &hit.response {
+            self.traffic.get_or_insert_default().record(response);
+        }
+    }
+
+    /// A sketch of the users, even when they're counted exactly, for storing stats in a
+    /// form that can be merged.
+    fn sketch(&self) -> HyperLogLog {
+        match &self.index {
+            UniqueIndex::Approximate(sketch) => sketch.clone(),
+            UniqueIndex::Exact(index) => {
+                let mut sketch = HyperLogLog::default();
+                for key in index {
+                    sketch.insert(key.sketch_hash());
+                }
+                sketch
+            }
         }
     }
 
     fn combine(&mut self, other: &ValueUniqueCounter) {
         self.total += other.total;
-        self.index = &self.index | &other.index;
-        self.unique = self.index.len();
+        if let Some(traffic) = &other.traffic {
+            self.traffic.get_or_insert_default().combine(traffic);
+        }
+        match (&mut self.index, &other.index) {
+            (UniqueIndex::Exact(index), UniqueIndex::Exact(other)) => index.extend(other),
+            (UniqueIndex::Approximate(sketch), UniqueIndex::Approximate(other)) => {
+                sketch.merge(other)
+            }
+            (UniqueIndex::Approximate(sketch), UniqueIndex::Exact(other)) => {
+                for key in other {
+                    sketch.insert(key.sketch_hash());
+                }
+            }
+            (UniqueIndex::Exact(index), UniqueIndex::Approximate(other)) => {
+                // Once either side is a sketch, the exact identifiers are gone.
+                let mut sketch = other.clone();
+                for key in index.iter() {
+                    sketch.insert(key.sketch_hash());
+                }
+                self.index = UniqueIndex::Approximate(sketch);
+            }
+        }
     }
 }
 
-impl Clone for ValueUniqueCounter {
-    fn clone(&self) -> Self {
-        ValueUniqueCounter {
-            total: self.total,
-            unique: self.unique,
-            index: self.index.clone(),
+impl Serialize for ValueUniqueCounter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ValueUniqueCounter", 5)?;
+        s.serialize_field("total", &self.total)?;
+        s.serialize_field("unique", &self.unique())?;
+        match &self.traffic {
+            None => {
+                s.skip_field("bytes")?;
+                s.skip_field("latency_ms")?;
+            }
+            Some(traffic) => {
+                s.serialize_field("bytes", &traffic.bytes)?;
+                s.serialize_field("latency_ms", &traffic.latency)?;
+            }
+        }
+        match &self.index {
+            UniqueIndex::Exact(_) => s.skip_field("sketch")?,
+            UniqueIndex::Approximate(sketch) => s.serialize_field("sketch", sketch)?,
         }
+        s.end()
     }
 }
 
-type ValueMap = BTreeMap<String, ValueUniqueCounter>;
+impl<'de> Deserialize<'de> for ValueUniqueCounter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        // `unique` is estimated again from the sketch
+        #[derive(Deserialize)]
+        struct Stored {
+            total: usize,
+            bytes: Option<u64>,
+            latency_ms: Option<traffic::Latency>,
+            sketch: Option<HyperLogLog>,
+        }
+
+        let stored = Stored::deserialize(deserializer)?;
+        let sketch = stored.sketch.ok_or_else(|| {
+            de::Error::custom(
+                "no sketch to merge uniques with, so these stats were counted with --exact or \
+                 by a kirby from before sketches, and can't be merged",
+            )
+        })?;
+        let traffic = stored.bytes.map(|bytes| Traffic {
+            bytes,
+            latency: stored.latency_ms.unwrap_or_default(),
+        });
+        Ok(ValueUniqueCounter {
+            total: stored.total,
+            index: UniqueIndex::Approximate(sketch),
+            traffic,
+        })
+    }
+}
+
+type ValueMap = BTreeMap<Symbol, ValueUniqueCounter>;
 type NameMap = EnumMap<FieldName, ValueMap>;
-type TimeMap = BTreeMap<String, NameMap>;
+pub type TimeMap = BTreeMap<String, Counters>;
+
+/// Every histogram for one time bucket: one per field, plus one per configured pivot.
+/// Serializes as a single map, with the pivots after the fields.
+#[derive(Clone, Debug, Default)]
+pub struct Counters {
+    fields: NameMap,
+    // Which optional fields were asked for, and are written out even when empty
+    optional: EnumMap<FieldName, bool>,
+    pivots: BTreeMap<String, ValueMap>,
+    // The values of every histogram, which only these histograms' symbols refer to
+    symbols: Symbols,
+}
+
+impl Counters {
+    /// Empty histograms for a time bucket, with every optional field and pivot that was
+    /// asked for, so that they're written out like the other fields even when nothing was
+    /// counted in them.
+    fn new(opts: &Options) -> Self {
+        let mut counters = Counters::default();
+        for (name, optional) in counters.optional.iter_mut() {
+            *optional = match name {
+                FieldName::gem | FieldName::gem_version => opts.gems,
+                name if name.is_response() => opts.responses,
+                name if name.is_rollup() => opts.rollups,
+                _ => false,
+            };
+        }
+        for pivot in &opts.pivots {
+            counters.pivots.insert(pivot.name.clone(), ValueMap::new());
+        }
+        counters
+    }
+
+    /// Iterates over every histogram by name, fields first and then pivots. Optional
+    /// fields are left out when they weren't asked for and are empty.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &ValueMap)> {
+        self.fields
+            .iter()
+            .filter(|&(name, values)| {
+                !name.is_optional() || self.optional[name] || !values.is_empty()
+            })
+            .map(|(name, values)| (name.as_str(), values))
+            .chain(
+                self.pivots
+                    .iter()
+                    .map(|(name, values)| (name.as_str(), values)),
+            )
+    }
+
+    /// Iterates over every histogram by name, in the same order as `iter`, with its
+    /// values in output order.
+    fn histograms(&self) -> impl Iterator<Item = (&str, SortedValues<'_>)> {
+        self.iter()
+            .map(|(name, values)| (name, SortedValues::new(name, values, &self.symbols)))
+    }
+
+    /// The counter for one value of a field, if it was counted.
+    pub fn get(&self, name: FieldName, value: &str) -> Option<&ValueUniqueCounter> {
+        self.fields[name].get(&self.symbols.get(value)?)
+    }
+
+    /// Adds a counter to the histogram with `name`, which is a field or a pivot.
+    fn add(&mut self, name: &str, value: &str, counter: &ValueUniqueCounter) {
+        let symbol = self.symbols.intern(value);
+        let values = match name.parse::<FieldName>() {
+            Ok(field) => &mut self.fields[field],
+            Err(_) => self.pivots.entry(name.to_string()).or_default(),
+        };
+        match values.get_mut(&symbol) {
+            Some(existing) => existing.combine(counter),
+            None => {
+                values.insert(symbol, counter.clone());
+            }
+        }
+    }
+
+    fn combine(&mut self, other: Counters) {
+        for (name, optional) in other.optional {
+            self.optional[name] |= optional;
+        }
+        let symbols = &mut self.symbols;
+        for (name, values) in other.fields {
+            combine_values(&mut self.fields[name], symbols, values, &other.symbols);
+        }
+        for (name, values) in other.pivots {
+            let left = self.pivots.entry(name).or_default();
+            combine_values(left, symbols, values, &other.symbols);
+        }
+    }
+}
+
+impl Index<FieldName> for Counters {
+    type Output = ValueMap;
+
+    fn index(&self, name: FieldName) -> &ValueMap {
+        &self.fields[name]
+    }
+}
+
+impl Serialize for Counters {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.iter().count()))?;
+        for (name, values) in self.histograms() {
+            map.serialize_entry(name, &values)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Counters {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let histograms: BTreeMap<String, BTreeMap<String, ValueUniqueCounter>> =
+            Deserialize::deserialize(deserializer)?;
+        let mut counters = Counters::default();
+        for (name, values) in histograms {
+            let histogram = match name.parse::<FieldName>() {
+                Ok(field) => {
+                    counters.optional[field] = field.is_optional();
+                    &mut counters.fields[field]
+                }
+                Err(_) => counters.pivots.entry(name).or_default(),
+            };
+            for (value, counter) in values {
+                histogram.insert(counters.symbols.intern(&value), counter);
+            }
+        }
+        Ok(counters)
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortKey<'a> {
+    Version(Version),
+    Text(&'a str),
+}
+
+/// A histogram in output order. Values of version fields are sorted by version, so that
+/// `2.10.0` comes after `2.9.0`, and pivot values are sorted field by field.
+struct SortedValues<'a> {
+    values: &'a ValueMap,
+    symbols: &'a Symbols,
+    // Whether each `/`-separated part of a value is a version
+    versions: Vec<bool>,
+}
+
+impl<'a> SortedValues<'a> {
+    fn new(name: &str, values: &'a ValueMap, symbols: &'a Symbols) -> Self {
+        let mut versions = Vec::new();
+        for field in name.split('+').filter_map(|f| f.parse::<FieldName>().ok()) {
+            match field {
+                FieldName::gem_version => versions.extend([false, true]),
+                field => versions.push(field.is_version()),
+            }
+        }
+        SortedValues {
+            values,
+            symbols,
+            versions,
+        }
+    }
+
+    fn entries(&self) -> Vec<(&'a str, &'a ValueUniqueCounter)> {
+        let mut entries: Vec<_> = self
+            .values
+            .iter()
+            .map(|(&value, counter)| (self.symbols.resolve(value), counter))
+            .collect();
+        // Symbols are in the order values were first seen, so sort by the strings
+        entries.sort_unstable_by_key(|&(value, _)| value);
+        if self.versions.contains(&true) {
+            // The sort is stable, so equal versions like `1.0` and `1` stay in string order
+            entries.sort_by_cached_key(|&(value, _)| self.key(value));
+        }
+        entries
+    }
+
+    fn key(&self, value: &'a str) -> Vec<SortKey<'a>> {
+        value
+            .split('/')
+            .zip(self.versions.iter().chain(std::iter::repeat(&false)))
+            .map(|(part, &version)| match version {
+                true => part.parse().map_or(SortKey::Text(part), SortKey::Version),
+                false => SortKey::Text(part),
+            })
+            .collect()
+    }
+}
+
+impl Serialize for SortedValues<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let entries = self.entries();
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (value, counter) in entries {
+            map.serialize_entry(value, counter)?;
+        }
+        map.end()
+    }
+}
+
+/// Stats for a set of log lines, along with counts of the lines that couldn't be counted.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub times: TimeMap,
+    pub skipped: Skipped,
+    /// The kind of bucket the times are keys of, unless it isn't known, or the stats of
+    /// different kinds were combined
+    pub bucket: Option<Bucket>,
+}
+
+impl Stats {
+    pub fn combine(mut self, other: Stats) -> Stats {
+        self.times = combine_stats(self.times, other.times);
+        self.skipped.combine(&other.skipped);
+        self.bucket = match (self.bucket, other.bucket) {
+            (Some(bucket), Some(other)) if bucket != other => None,
+            (bucket, other) => bucket.or(other),
+        };
+        self
+    }
+
+    /// Combines the time buckets into longer ones that each fits inside, like days into
+    /// months. Stats that don't know their bucket are taken to be days, or hours or months
+    /// if their keys look like it.
+    pub fn rebucket(&mut self, bucket: Bucket) -> std::result::Result<(), String> {
+        let keys = self
+            .times
+            .keys()
+            .map(|time| {
+                let from = self.bucket.or_else(|| Bucket::of_key(time));
+                let from = from.ok_or_else(|| format!("{:?} isn't a bucket key", time))?;
+                bucket.rekey(from, time)
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (key, (_, counters)) in keys.into_iter().zip(std::mem::take(&mut self.times)) {
+            self.times.entry(key).or_default().combine(counters);
+        }
+        self.bucket = Some(bucket);
+        Ok(())
+    }
+
+    /// Folds the values past each histogram's limit into `__other__`. Limiting has to
+    /// happen after combining, since a value that is rare in one file can be common
+    /// across all of them.
+    pub fn limit(&mut self, limits: &Limits) {
+        for counters in self.times.values_mut() {
+            for (name, values) in counters.fields.iter_mut() {
+                if let Some(limit) = limits.get(name.as_str()) {
+                    limit_values(values, &mut counters.symbols, limit);
+                }
+            }
+            for (name, values) in counters.pivots.iter_mut() {
+                if let Some(limit) = limits.get(name) {
+                    limit_values(values, &mut counters.symbols, limit);
+                }
+            }
+        }
+    }
+}
+
+/// The value that histograms fold their least common values into when limited.
+pub const OTHER: &str = "__other__";
+
+/// How many values each histogram keeps, by field or pivot name, or for all of them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub all: Option<usize>,
+    pub names: BTreeMap<String, usize>,
+}
+
+impl Limits {
+    /// Adds a limit like `platform=50`, `ruby+bundler=20` (or `ruby,bundler=20`), or `100`
+    /// for every histogram without a limit of its own. A pivot's limit applies whatever
+    /// order its fields were given in.
+    pub fn add(&mut self, spec: &str) -> std::result::Result<(), String> {
+        let (name, limit) = match spec.split_once('=') {
+            Some((name, limit)) => (Some(name), limit),
+            None => (None, spec),
+        };
+        let limit = limit
+            .parse()
+            .map_err(|_| format!("invalid limit {:?}", spec))?;
+        match name {
+            None => self.all = Some(limit),
+            Some(name) if name.contains([',', '+']) => {
+                let pivot: Pivot = name.parse()?;
+                self.names.insert(Self::key(pivot.name()), limit);
+            }
+            Some(name) => {
+                name.parse::<FieldName>()?;
+                self.names.insert(name.to_string(), limit);
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Option<usize> {
+        self.names.get(&Self::key(name)).copied().or(self.all)
+    }
+
+    /// Sorts a pivot's field names, so `bundler+ruby` finds the limit for `ruby+bundler`.
+    fn key(name: &str) -> String {
+        let mut fields: Vec<&str> = name.split('+').collect();
+        fields.sort_unstable();
+        fields.join("+")
+    }
+}
+
+/// Keeps the `limit` values with the highest totals, and combines the rest into one
+/// `__other__` counter, so totals and uniques still cover every request.
+fn limit_values(values: &mut ValueMap, symbols: &mut Symbols, limit: usize) {
+    let other_symbol = symbols.intern(OTHER);
+    let mut other = values.remove(&other_symbol);
+    if values.len() > limit {
+        let mut ranked: Vec<(Symbol, usize)> =
+            values.iter().map(|(&value, c)| (value, c.total)).collect();
+        // Ties go to the value that sorts first, so limiting is deterministic
+        ranked.sort_by(|a, b| {
+            let (a_value, b_value) = (symbols.resolve(a.0), symbols.resolve(b.0));
+            b.1.cmp(&a.1).then_with(|| a_value.cmp(b_value))
+        });
+        let dropped: Vec<Symbol> = ranked[limit..].iter().map(|&(v, _)| v).collect();
+        for value in dropped {
+            let counter = values.remove(&value).unwrap();
+            match &mut other {
+                Some(other) => other.combine(&counter),
+                None => other = Some(counter),
+            }
+        }
+    }
+    if let Some(other) = other {
+        values.insert(other_symbol, other);
+    }
+}
+
+/// Why a log line couldn't be counted.
+#[derive(Debug)]
+pub enum LineError {
+    Encoding,
+    Json(serde_json::Error),
+    ClientIp(AddrParseError),
+    Timestamp,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LineError::Encoding => f.write_str("invalid UTF-8"),
+            LineError::Json(e) => write!(f, "json parse error: {}", e),
+            LineError::ClientIp(e) => write!(f, "ipaddr parse error: {}", e),
+            LineError::Timestamp => f.write_str("missing or invalid timestamp"),
+        }
+    }
+}
+
+impl std::error::Error for LineError {}
+
+/// Counts of lines skipped in lenient mode, by reason. Lines with a user agent that
+/// couldn't be parsed are still counted for every field that doesn't come from the user
+/// agent, so `user_agent` counts those rather than skipped lines.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Skipped {
+    pub encoding: usize,
+    pub json: usize,
+    pub client_ip: usize,
+    pub timestamp: usize,
+    pub user_agent: usize,
+    /// Files that couldn't be read to the end, like truncated gzip or zstd, so the rest
+    /// of their lines weren't counted.
+    pub unreadable: usize,
+}
+
+impl Skipped {
+    fn record(&mut self, error: &LineError) {
+        match error {
+            LineError::Encoding => self.encoding += 1,
+            LineError::Json(_) => self.json += 1,
+            LineError::ClientIp(_) => self.client_ip += 1,
+            LineError::Timestamp => self.timestamp += 1,
+        }
+    }
+
+    fn combine(&mut self, other: &Skipped) {
+        self.encoding += other.encoding;
+        self.json += other.json;
+        self.client_ip += other.client_ip;
+        self.timestamp += other.timestamp;
+        self.user_agent += other.user_agent;
+        self.unreadable += other.unreadable;
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct Options {
     pub verbose: bool,
     pub unknown: bool,
+    /// Count uniques exactly with a set of identifiers instead of a HyperLogLog sketch.
+    /// Uses far more memory, and the output can't be merged with other runs.
+    pub exact: bool,
+    /// How client IPs are turned into the identifiers uniques are counted by.
+    pub identifier: IdentifierStrategy,
+    /// What counts as one user: the IP, the IP and user agent, or Bundler's command UID.
+    pub unique_by: UniqueBy,
+    /// Which requests are counted, so that each command is counted once.
+    pub rules: Rules,
+    /// Also sum up bytes served and a histogram of response times for every count.
+    pub traffic: bool,
+    /// Count response statuses and cache states, over every request rather than one
+    /// request per command.
+    pub responses: bool,
+    /// Also count ruby, rubygems, and bundler versions by major and major.minor release.
+    pub rollups: bool,
+    pub bucket: Bucket,
+    /// Composite dimensions to count alongside the single fields.
+    pub pivots: Vec<Pivot>,
+    /// Count successful `.gem` downloads per gem and per gem version.
+    pub gems: bool,
+    /// Skip lines that can't be counted, instead of panicking, and count them by reason.
+    pub lenient: bool,
+    /// How many values to keep in each histogram, applied by `Stats::limit`.
+    pub limits: Limits,
+    /// Where to write the lines that couldn't be counted.
+    pub dead_letter: Option<DeadLetter>,
+    /// Only lines that match are counted.
+    pub filter: Option<filter::Filter>,
     pub paths: Vec<String>,
 }
 
+/// Adds the counters of `right` into `left`, interning `right`'s values into `left`'s
+/// table.
+fn combine_values(
+    left: &mut ValueMap,
+    left_symbols: &mut Symbols,
+    right: ValueMap,
+    right_symbols: &Symbols,
+) {
+    for (value, counter) in right {
+        let value = left_symbols.intern(right_symbols.resolve(value));
+        let left_counter = left.entry(value).or_default();
+        left_counter.combine(&counter);
+    }
+}
+
 pub fn combine_stats(mut left: TimeMap, right: TimeMap) -> TimeMap {
-    for (time, names) in right {
-        let left_names = left.entry(time.to_string()).or_default();
-        for (name, versions) in names {
-            let left_versions = &mut left_names[name];
-            for (version, counter) in versions {
-                let left_counter = left_versions.entry(version.to_string()).or_default();
-                left_counter.combine(&counter);
-            }
-        }
+    for (time, counters) in right {
+        left.entry(time).or_default().combine(counters);
     }
 
     left
 }
 
-fn duplicate_request(r: &request::Request) -> bool {
-    if r.shared.request_path == "/api/v1/dependencies" {
-        // Requests for dependencies are recursive, and so we want to count only one
-        // request per time a user runs a command, rather than every request that was
-        // made to satisfy that command. It seems like RubyGems makes one HEAD
-        // request with no query, and Bundler makes one GET request with no query,
-        // per command that is run. We ignore the rest for stats purposes.
-        !r.shared.request_query.is_empty()
-    } else {
-        // Versions that don't use the Dependency API make one request, either for
-        // specs or for versions. We want to count each of those.
-        !METADATA_PATHS.contains(&r.shared.request_path.as_ref())
-    }
+/// One request to count: who made it, and what was served when counting traffic.
+#[derive(Clone, Copy)]
+struct Hit {
+    user: UserIdentifier,
+    response: Option<Response>,
 }
 
-fn increment(counters: &mut NameMap, name: FieldName, value: &str, key: UserIdentifier) {
-    let counter = counters[name].entry(String::from(value)).or_default();
-    counter.increment(key);
+fn increment(values: &mut ValueMap, symbols: &mut Symbols, value: &str, hit: Hit, opts: &Options) {
+    let counter = values
+        .entry(symbols.intern(value))
+        .or_insert_with(|| ValueUniqueCounter::new(opts.exact));
+    counter.increment(hit);
 }
 
-fn increment_maybe(
-    counters: &mut NameMap,
-    name: FieldName,
-    maybe_value: Option<&str>,
-    key: UserIdentifier,
+fn increment_pivot(
+    counters: &mut Counters,
+    pivot: &Pivot,
+    values: &EnumMap<FieldName, Option<&str>>,
+    hit: Hit,
+    opts: &Options,
 ) {
-    if let Some(value) = maybe_value {
-        increment(counters, name, value, key);
+    let mut value = String::new();
+    for (i, &name) in pivot.fields.iter().enumerate() {
+        match values[name] {
+            Some(v) => {
+                if i > 0 {
+                    value.push('/');
+                }
+                value.push_str(v);
+            }
+            None => return,
+        }
+    }
+
+    let pivot_values = counters
+        .pivots
+        .get_mut(&pivot.name)
+        .expect("every pivot has a histogram");
+    increment(pivot_values, &mut counters.symbols, &value, hit, opts);
+}
+
+/// Returns the gem name and version for a successful `.gem` download.
+fn gem_download<'r>(r: &'r request::Request) -> Option<(&'r str, &'r str)> {
+    if !r.response_status.as_ref().is_none_or(|s| s.is_success()) {
+        return None;
     }
+    let full_name = DOWNLOADS.full_name(&r.shared.request_path)?;
+    let (gem, version, _platform) = DOWNLOADS.split_full_name(full_name)?;
+    Some((gem, version))
 }
 
 pub fn print_unknown_user_agents(path: &str, opts: &Options) {
     let ctx = user_agent::ParseCtx::new();
     let capture_locations = &mut ctx.capture_locations();
-    file::reader(path, opts).split(b'\n').for_each(|line| {
+    let file = file::reader(path, opts).unwrap_or_else(|e| panic!("couldn't read {}: {}", path, e));
+    file.split(b'\n').for_each(|line| {
         let l = &line.unwrap();
         let r: request::Request = serde_json::from_slice(l).unwrap();
         if ctx
@@ -152,188 +927,481 @@ pub fn print_unknown_user_agents(path: &str, opts: &Options) {
 pub fn count_line(
     ctx: &user_agent::ParseCtx,
     capture_locations: &mut ParseCaptureLocations,
-    times: &mut TimeMap,
+    stats: &mut Stats,
     line: &str,
-) {
-    let r: request::Request = serde_json::from_str(line).unwrap();
+    opts: &Options,
+) -> std::result::Result<(), LineError> {
+    let r = request::Request::from_line(line).map_err(LineError::Json)?;
+    if let Some(filter) = &opts.filter {
+        let ua = filter
+            .uses_user_agent()
+            .then(|| ctx.parse(capture_locations, &r.shared.user_agent))
+            .flatten();
+        if !filter.matches(&r, ua.as_ref()) {
+            return Ok(());
+        }
+    }
 
-    if duplicate_request(&r) {
-        return;
+    // Gem downloads are never the one request per command that everything else counts,
+    // so they're counted before skipping duplicates.
+    let download = if opts.gems { gem_download(&r) } else { None };
+    let counted = download.is_none()
+        && opts.rules.counts(
+            r.method.as_deref(),
+            r.shared.request_path.as_ref(),
+            r.shared.request_query.as_ref(),
+        );
+    // Responses describe what was served rather than who ran a command, so every request
+    // counts towards them.
+    if download.is_none() && !counted && !opts.responses {
+        return Ok(());
     }
 
-    let date = r.shared.timestamp.get(..10).unwrap().to_string();
-    let counters = times.entry(date).or_default();
+    let time = opts
+        .bucket
+        .key(r.shared.timestamp.as_ref())
+        .ok_or(LineError::Timestamp)?;
+    let ip = r.client_ip.parse().map_err(LineError::ClientIp)?;
+    let timestamp = r.shared.timestamp.as_ref();
+    let user_agent = r.shared.user_agent.as_ref();
+    let counters = stats
+        .times
+        .entry(time)
+        .or_insert_with(|| Counters::new(opts));
 
-    let user_key = r.client_ip.parse().expect("ipaddr parse error");
+    let status = r.response_status.as_ref().map(|s| s.to_string());
+    let mut rollups: EnumMap<FieldName, Option<String>> = EnumMap::default();
+    let mut values: EnumMap<FieldName, Option<&str>> = EnumMap::default();
+    values[FieldName::tls_cipher] = Some(r.shared.tls_cipher.as_ref());
+    values[FieldName::server_region] = r.server_region.as_deref();
+    values[FieldName::server_datacenter] = r.server_datacenter.as_deref();
+    values[FieldName::client_continent] = r.client_continent.as_deref();
+    values[FieldName::client_country] = r.client_country.as_deref();
+    if opts.responses {
+        values[FieldName::response_status] = status.as_deref();
+        values[FieldName::response_class] = r.response_status.as_ref().map(|s| s.class());
+        values[FieldName::cache_state] = r.cache_state.as_deref();
+        values[FieldName::response_cache] = r.response_cache.as_deref();
+    }
 
-    increment(
-        counters,
-        FieldName::tls_cipher,
-        r.shared.tls_cipher.as_ref(),
-        user_key,
-    );
-    if let Some(ua) = ctx.parse(capture_locations, r.shared.user_agent.as_ref()) {
-        increment_maybe(counters, FieldName::rubygems, ua.rubygems, user_key);
-        increment_maybe(counters, FieldName::bundler, ua.bundler, user_key);
-        increment_maybe(counters, FieldName::ruby, ua.ruby, user_key);
-        increment_maybe(counters, FieldName::platform, ua.platform, user_key);
-        increment_maybe(counters, FieldName::ci, ua.ci, user_key);
-        increment_maybe(counters, FieldName::gemstash, ua.gemstash, user_key);
+    let mut uid = None;
+    if counted || opts.unique_by == UniqueBy::BundlerUid || opts.responses {
+        match ctx.parse(capture_locations, user_agent) {
+            Some(ua) => {
+                values[FieldName::rubygems] = ua.rubygems;
+                values[FieldName::bundler] = ua.bundler;
+                values[FieldName::ruby] = ua.ruby;
+                values[FieldName::platform] = ua.platform;
+                values[FieldName::ci] = ua.ci;
+                values[FieldName::gemstash] = ua.gemstash;
+                uid = ua.uid;
+                if opts.rollups {
+                    for (version, major, minor) in [
+                        (ua.ruby, FieldName::ruby_major, FieldName::ruby_minor),
+                        (
+                            ua.rubygems,
+                            FieldName::rubygems_major,
+                            FieldName::rubygems_minor,
+                        ),
+                        (
+                            ua.bundler,
+                            FieldName::bundler_major,
+                            FieldName::bundler_minor,
+                        ),
+                    ] {
+                        if let Some(Ok(version)) = version.map(str::parse::<Version>) {
+                            rollups[major] = Some(version.release_line(1));
+                            rollups[minor] = Some(version.release_line(2));
+                        }
+                    }
+                }
+            }
+            None if counted => stats.skipped.user_agent += 1,
+            None => {}
+        }
+    }
+    for (name, line) in &rollups {
+        if line.is_some() {
+            values[name] = line.as_deref();
+        }
+    }
+    let hit = Hit {
+        user: opts
+            .unique_by
+            .identify(&opts.identifier, ip, user_agent, uid, timestamp),
+        response: opts.traffic.then(|| Response {
+            bytes: r.response_bytes.unwrap_or(0),
+            elapsed_ms: r.time_elapsed,
+        }),
+    };
+
+    if let Some((gem, version)) = download {
+        let symbols = &mut counters.symbols;
+        increment(
+            &mut counters.fields[FieldName::gem],
+            symbols,
+            gem,
+            hit,
+            opts,
+        );
+        let gem_version = [gem, version].join("/");
+        increment(
+            &mut counters.fields[FieldName::gem_version],
+            symbols,
+            &gem_version,
+            hit,
+            opts,
+        );
+    }
+
+    for (name, value) in &values {
+        if let Some(value) = value
+            && (counted || name.is_response())
+        {
+            increment(
+                &mut counters.fields[name],
+                &mut counters.symbols,
+                value,
+                hit,
+                opts,
+            );
+        }
+    }
+    for pivot in &opts.pivots {
+        if counted || pivot.fields.iter().any(FieldName::is_response) {
+            increment_pivot(counters, pivot, &values, hit, opts);
+        }
     }
+
+    Ok(())
 }
 
-pub fn stream_stats<'a>(mut stream: Box<dyn BufRead + 'a>, opts: &Options) -> TimeMap {
-    let mut times = TimeMap::default();
-    let mut lineno = 0;
+pub fn stream_stats<'a>(stream: Box<dyn BufRead + 'a>, source: &str, opts: &Options) -> Stats {
+    chunked_stream_stats(stream, source, opts, chunk::CHUNK_SIZE)
+}
+
+/// A line that couldn't be counted, kept until the lines before it have been handled.
+struct Rejection {
+    line: usize,
+    error: LineError,
+    record: String,
+}
 
+// Reads the stream in chunks of whole lines, and counts a batch of chunks at a time in
+// parallel. Rejected lines are handled afterwards in line order, so the dead letter
+// output and the line a strict run stops on don't depend on how the work was split up.
+fn chunked_stream_stats<'a>(
+    stream: Box<dyn BufRead + 'a>,
+    source: &str,
+    opts: &Options,
+    chunk_size: usize,
+) -> Stats {
+    let mut stats = Stats::default();
+    let mut chunks = chunk::ChunkReader::new(stream, chunk_size);
     let ctx = user_agent::ParseCtx::new();
-    let capture_locations = &mut ctx.capture_locations();
-    let mut line = String::with_capacity(1024 * 1024);
 
     loop {
-        line.clear();
-        match stream.read_line(&mut line) {
-            Ok(0) => break,
-            Ok(_) => {}
-            Err(e) => {
+        let lines_before = chunks.lines();
+        let batch = match chunks.next_batch(rayon::current_num_threads() * 2) {
+            Ok(batch) if batch.is_empty() => break,
+            Ok(batch) => batch,
+            Err(e) if opts.lenient => {
                 if opts.verbose {
-                    eprintln!("Failed to read line:\n  {}", e);
+                    eprintln!(
+                        "Failed to read {} after line {}:\n  {}",
+                        source, lines_before, e
+                    );
                 }
-                continue;
+                stats.skipped.unreadable += 1;
+                break;
             }
+            Err(e) => panic!(
+                "couldn't read {} after line {}: {}",
+                source, lines_before, e
+            ),
+        };
+
+        let counted: Vec<(Stats, Vec<Rejection>)> = batch
+            .par_iter()
+            .map(|chunk| chunk_stats(&ctx, chunk, opts))
+            .collect();
+        for (chunk_stats, rejections) in counted {
+            for rejection in rejections {
+                if let Some(dead_letter) = &opts.dead_letter {
+                    dead_letter
+                        .record(source, rejection.line, &rejection.error, &rejection.record)
+                        .expect("couldn't write rejected line");
+                }
+                if !opts.lenient {
+                    panic!("{} on line {}", rejection.error, rejection.line);
+                }
+            }
+            stats = stats.combine(chunk_stats);
         }
 
         if opts.verbose {
-            lineno += 1;
-            if lineno % 100_000 == 0 {
+            for _ in lines_before / 100_000..chunks.lines() / 100_000 {
                 print!(".");
-                stdout().flush().unwrap();
             }
+            stdout().flush().unwrap();
         }
-
-        count_line(&ctx, capture_locations, &mut times, line.as_str());
     }
 
     if opts.verbose {
         println!();
     }
 
-    times
+    stats.bucket = Some(opts.bucket);
+    stats
+}
+
+fn chunk_stats(
+    ctx: &user_agent::ParseCtx,
+    chunk: &chunk::Chunk,
+    opts: &Options,
+) -> (Stats, Vec<Rejection>) {
+    let mut stats = Stats::default();
+    let mut rejections = Vec::new();
+    let capture_locations = &mut ctx.capture_locations();
+
+    for (lineno, line) in chunk.lines() {
+        let (result, line) = match line {
+            Ok(line) => (
+                count_line(ctx, capture_locations, &mut stats, line, opts),
+                Cow::Borrowed(line),
+            ),
+            Err(bytes) => (Err(LineError::Encoding), String::from_utf8_lossy(bytes)),
+        };
+        if let Err(error) = result {
+            if opts.lenient {
+                stats.skipped.record(&error);
+            }
+            let record = match opts.dead_letter {
+                Some(_) => line.to_string(),
+                None => String::new(),
+            };
+            rejections.push(Rejection {
+                line: lineno,
+                error,
+                record,
+            });
+            // A strict run stops at the first rejected line anyway
+            if !opts.lenient {
+                break;
+            }
+        }
+    }
+
+    (stats, rejections)
 }
 
-#[inline]
-pub fn file_stats(path: &str, opts: &Options) -> TimeMap {
-    let file_stream = file::reader(path, opts);
-    stream_stats(file_stream, opts)
+/// Reads stats back from the JSON that `kirby` prints, or from the bare stats that the S3
+/// Lambda uploads, so that they can be combined with others. Uniques are merged by their
+/// sketches, so stats counted with `--exact`, or before uniques had sketches, can't be read.
+pub fn read_stats<R: Read>(reader: R) -> serde_json::Result<Stats> {
+    let mut json: serde_json::Value = serde_json::from_reader(reader)?;
+    if json.get("ran_at").is_none() {
+        return Ok(Stats {
+            times: serde_json::from_value(json)?,
+            ..Default::default()
+        });
+    }
+    let skipped = match json["skipped"].take() {
+        serde_json::Value::Null => Skipped::default(),
+        skipped => serde_json::from_value(skipped)?,
+    };
+    Ok(Stats {
+        times: serde_json::from_value(json["stats"].take())?,
+        skipped,
+        bucket: serde_json::from_value(json["bucket"].take())?,
+    })
+}
+
+pub fn file_stats(path: &str, opts: &Options) -> Result<Stats> {
+    let file_stream = file::reader(path, opts)?;
+    Ok(stream_stats(file_stream, path, opts))
 }
 
 pub fn file_clickhouse<W>(w: &mut W, path: &str, context: &clickhouse::Context) -> Result<()>
 where
     W: Write,
 {
-    let file_stream = file::reader(path, &Default::default());
-    clickhouse(w, file_stream, context)
+    let file_stream = file::reader(path, &Default::default())?;
+    clickhouse(w, file_stream, path, context)
 }
 
+/// Writes the ClickHouse row for every gem download in the stream. Records that can't be
+/// turned into a row are errors, unless the context has a dead letter sink, in which case
+/// they are written there and skipped.
 pub fn clickhouse<W>(
     w: &mut W,
-    mut file_stream: Box<dyn BufRead>,
+    file_stream: Box<dyn BufRead + '_>,
+    source: &str,
+    context: &clickhouse::Context,
+) -> Result<()>
+where
+    W: Write,
+{
+    chunked_clickhouse(w, file_stream, source, context, chunk::CHUNK_SIZE)
+}
+
+// Like stream stats, converts a batch of chunks in parallel, then writes each chunk's rows
+// and rejected lines in order, so the output is the same as converting line by line.
+fn chunked_clickhouse<W>(
+    w: &mut W,
+    file_stream: Box<dyn BufRead + '_>,
+    source: &str,
     context: &clickhouse::Context,
+    chunk_size: usize,
 ) -> Result<()>
 where
     W: Write,
 {
-    let mut line = String::with_capacity(1024 * 1024);
+    let mut chunks = chunk::ChunkReader::new(file_stream, chunk_size);
 
     loop {
-        line.clear();
-        if file_stream.read_line(&mut line)? == 0 {
-            break;
-        }
-        let mut clickhouse: request::Clickhouse = serde_json::from_str(&line)?;
-        if !clickhouse.response_status.is_success() {
-            continue;
+        let batch = chunks.next_batch(rayon::current_num_threads() * 2)?;
+        if batch.is_empty() {
+            return Ok(());
         }
-        if !context
-            .download_pattern
-            .is_match(&clickhouse.shared.request_path)
-        {
-            continue;
-        }
-
-        match (&clickhouse.gem, &clickhouse.version, &clickhouse.platform) {
-            (Some(_), Some(_), Some(_)) => {}
-            (None, None, None) => {
-                let request_path = clickhouse.shared.request_path.as_ref();
-                let full_name = &request_path[6..request_path.len() - 4];
-                let mut parts = full_name.splitn(3, '-');
-                let gem = parts.next().map(Cow::Borrowed);
-                let version = parts.next().map(Cow::Borrowed);
-                if gem.is_some() && version.is_some() && parts.remainder().is_none() {
-                    clickhouse.gem = gem;
-                    clickhouse.version = version;
-                    clickhouse.platform = Some(Cow::Borrowed("ruby"));
-                } else {
-                    let (name_len, version_len) =
-                        context.full_name_lengths.get(full_name).map_or_else(
-                            || {
-                                if clickhouse.response_status.not_modified() {
-                                    Ok(&(0, 0))
-                                } else {
-                                    Err(Error::new(
-                                        ErrorKind::InvalidData,
-                                        format!(
-                                            "unknown full name: {full_name:?} in {:?}",
-                                            clickhouse
-                                        ),
-                                    ))
-                                }
-                            },
-                            Ok,
-                        )?;
-
-                    // 304s can be missing the headers for gem, version, platform
-                    // Don't error if that's the case, just continue, since it is such
-                    // a small percentage of the requests.
-                    if *name_len == 0 && *version_len == 0 {
-                        continue;
-                    }
 
-                    let name_end = *name_len as usize;
-                    let version_end = name_end + 1 + *version_len as usize;
-                    clickhouse.gem = Some(Cow::Borrowed(&full_name[..name_end]));
-                    clickhouse.version = Some(Cow::Borrowed(&full_name[name_end + 1..version_end]));
-                    if version_end < full_name.len() {
-                        clickhouse.platform = Some(Cow::Borrowed(&full_name[version_end + 1..]));
-                    } else {
-                        clickhouse.platform = Some(Cow::Borrowed("ruby"));
-                    }
+        let converted: Vec<_> = batch
+            .par_iter()
+            .map(|chunk| chunk_clickhouse(chunk, context))
+            .collect();
+        for (rows, rejections, error) in converted {
+            w.write_all(&rows)?;
+            for (line, e, record) in rejections {
+                if let Some(dead_letter) = &context.dead_letter {
+                    dead_letter.record(source, line, &e, &record)?;
                 }
             }
-            _ => {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    format!(
-                        "missing gem, version, or platform from request in {:?}",
-                        clickhouse,
-                    ),
-                ));
+            if let Some(e) = error {
+                return Err(e);
             }
         }
-        serde_json::to_writer(&mut *w, &clickhouse)?;
-        w.write_all(b"\n")?;
     }
-    Ok(())
+}
+
+// Returns the rows, the lines rejected for the dead letter sink, and the error that
+// stopped the chunk, if one did.
+#[allow(clippy::type_complexity)]
+fn chunk_clickhouse(
+    chunk: &chunk::Chunk,
+    context: &clickhouse::Context,
+) -> (Vec<u8>, Vec<(usize, Error, String)>, Option<Error>) {
+    let mut rows = Vec::new();
+    let mut rejections = Vec::new();
+
+    for (lineno, line) in chunk.lines() {
+        let (result, line) = match line {
+            Ok(line) => (
+                clickhouse_line(&mut rows, line, context),
+                Cow::Borrowed(line),
+            ),
+            Err(bytes) => (
+                Err(rejected("invalid UTF-8".to_string())),
+                String::from_utf8_lossy(bytes),
+            ),
+        };
+        match result {
+            Ok(()) => {}
+            Err(ClickhouseError::Rejected(e)) if context.dead_letter.is_some() => {
+                rejections.push((lineno, e, line.into_owned()));
+            }
+            Err(ClickhouseError::Rejected(e) | ClickhouseError::Write(e)) => {
+                return (rows, rejections, Some(e));
+            }
+        }
+    }
+
+    (rows, rejections, None)
+}
+
+// Why a line didn't become a row. Records that can't be parsed or resolved, including
+// truncated JSON, go to the dead letter sink if there is one, but a failed write always
+// stops the conversion.
+enum ClickhouseError {
+    Rejected(Error),
+    Write(Error),
+}
+
+impl From<serde_json::Error> for ClickhouseError {
+    fn from(e: serde_json::Error) -> Self {
+        ClickhouseError::Rejected(e.into())
+    }
+}
+
+fn rejected(message: String) -> ClickhouseError {
+    ClickhouseError::Rejected(Error::new(ErrorKind::InvalidData, message))
+}
+
+fn clickhouse_line<W>(
+    w: &mut W,
+    line: &str,
+    context: &clickhouse::Context,
+) -> std::result::Result<(), ClickhouseError>
+where
+    W: Write,
+{
+    let mut clickhouse: request::Clickhouse = serde_json::from_str(line)?;
+    if clickhouse.shared.timestamp.is_empty() {
+        return Err(rejected("missing timestamp".to_string()));
+    }
+    if !clickhouse.response_status.is_success() {
+        return Ok(());
+    }
+    let Some(full_name) = context.full_name(&clickhouse.shared.request_path) else {
+        return Ok(());
+    };
+
+    match (&clickhouse.gem, &clickhouse.version, &clickhouse.platform) {
+        (Some(_), Some(_), Some(_)) => {}
+        (None, None, None) => match context.split_full_name(full_name) {
+            Some((gem, version, platform)) => {
+                clickhouse.gem = Some(Cow::Borrowed(gem));
+                clickhouse.version = Some(Cow::Borrowed(version));
+                clickhouse.platform = Some(Cow::Borrowed(platform));
+            }
+            // 304s can be missing the headers for gem, version, platform
+            // Don't error if that's the case, just continue, since it is such
+            // a small percentage of the requests.
+            None if clickhouse.response_status.not_modified() => return Ok(()),
+            None => {
+                return Err(rejected(format!(
+                    "unknown full name: {full_name:?} in {:?}",
+                    clickhouse
+                )));
+            }
+        },
+        _ => {
+            return Err(rejected(format!(
+                "missing gem, version, or platform from request in {:?}",
+                clickhouse,
+            )));
+        }
+    }
+    if let Some(filter) = &context.filter {
+        let ua = filter
+            .uses_user_agent()
+            .then(|| user_agent::parse_user_agent(&clickhouse.shared.user_agent))
+            .flatten();
+        if !filter.matches(&clickhouse, ua.as_ref()) {
+            return Ok(());
+        }
+    }
+    serde_json::to_writer(&mut *w, &clickhouse).map_err(|e| ClickhouseError::Write(e.into()))?;
+    w.write_all(b"\n").map_err(ClickhouseError::Write)
 }
 
 #[test]
 fn test_sample_10_file_stats() {
     let opts = Options {
-        verbose: false,
-        unknown: false,
         paths: vec!["test/sample_10.log".to_string()],
+        exact: true,
+        ..Default::default()
     };
-    let actual = file_stats("test/sample_10.log", &opts);
+    let actual = file_stats("test/sample_10.log", &opts).unwrap();
 
     expect_test::expect![[r#"
         {
@@ -344,7 +1412,46 @@ fn test_sample_10_file_stats() {
                 "unique": 2
               }
             },
-            "server_region": {},
+            "server_region": {
+              "APAC": {
+                "total": 1,
+                "unique": 1
+              },
+              "US-East": {
+                "total": 1,
+                "unique": 1
+              }
+            },
+            "server_datacenter": {
+              "DCA": {
+                "total": 1,
+                "unique": 1
+              },
+              "MEL": {
+                "total": 1,
+                "unique": 1
+              }
+            },
+            "client_continent": {
+              "NA": {
+                "total": 1,
+                "unique": 1
+              },
+              "OC": {
+                "total": 1,
+                "unique": 1
+              }
+            },
+            "client_country": {
+              "Australia": {
+                "total": 1,
+                "unique": 1
+              },
+              "United States": {
+                "total": 1,
+                "unique": 1
+              }
+            },
             "rubygems": {
               "2.2.5": {
                 "total": 1,
@@ -385,17 +1492,17 @@ fn test_sample_10_file_stats() {
             "gemstash": {}
           }
         }"#]]
-    .assert_eq(&serde_json::to_string_pretty(&actual).unwrap());
+    .assert_eq(&serde_json::to_string_pretty(&actual.times).unwrap());
 }
 
 #[test]
 fn test_sample_10_dups_file_stats() {
     let opts = Options {
-        verbose: false,
-        unknown: false,
         paths: vec!["test/sample_10_dups.log".to_string()],
+        exact: true,
+        ..Default::default()
     };
-    let actual = file_stats("test/sample_10_dups.log", &opts);
+    let actual = file_stats("test/sample_10_dups.log", &opts).unwrap();
 
     expect_test::expect![[r#"
         {
@@ -406,7 +1513,46 @@ fn test_sample_10_dups_file_stats() {
                 "unique": 2
               }
             },
-            "server_region": {},
+            "server_region": {
+              "APAC": {
+                "total": 2,
+                "unique": 1
+              },
+              "US-East": {
+                "total": 2,
+                "unique": 1
+              }
+            },
+            "server_datacenter": {
+              "DCA": {
+                "total": 2,
+                "unique": 1
+              },
+              "MEL": {
+                "total": 2,
+                "unique": 1
+              }
+            },
+            "client_continent": {
+              "NA": {
+                "total": 2,
+                "unique": 1
+              },
+              "OC": {
+                "total": 2,
+                "unique": 1
+              }
+            },
+            "client_country": {
+              "Australia": {
+                "total": 2,
+                "unique": 1
+              },
+              "United States": {
+                "total": 2,
+                "unique": 1
+              }
+            },
             "rubygems": {
               "2.2.5": {
                 "total": 2,
@@ -447,7 +1593,193 @@ fn test_sample_10_dups_file_stats() {
             "gemstash": {}
           }
         }"#]]
-    .assert_eq(&serde_json::to_string_pretty(&actual).unwrap());
+    .assert_eq(&serde_json::to_string_pretty(&actual.times).unwrap());
+}
+
+#[test]
+fn test_sample_500_gems() {
+    let opts = Options {
+        exact: true,
+        gems: true,
+        ..Default::default()
+    };
+    let actual =
+        serde_json::to_value(file_stats("test/sample_500.log", &opts).unwrap().times).unwrap();
+
+    expect_test::expect![[r#"
+        [
+          {
+            "aws-sdk-codedeploy": {
+              "total": 1,
+              "unique": 1
+            },
+            "capistrano-sidekiq": {
+              "total": 1,
+              "unique": 1
+            },
+            "open4": {
+              "total": 1,
+              "unique": 1
+            },
+            "rack-protection": {
+              "total": 1,
+              "unique": 1
+            }
+          },
+          {
+            "aws-sdk-codedeploy/1.3.0": {
+              "total": 1,
+              "unique": 1
+            },
+            "capistrano-sidekiq/0.10.0": {
+              "total": 1,
+              "unique": 1
+            },
+            "open4/1.3.4": {
+              "total": 1,
+              "unique": 1
+            },
+            "rack-protection/1.5.3": {
+              "total": 1,
+              "unique": 1
+            }
+          },
+          {
+            "2.3.1": {
+              "total": 1,
+              "unique": 1
+            }
+          }
+        ]"#]]
+    .assert_eq(
+        &serde_json::to_string_pretty(&[
+            &actual["2018-03-15"]["gem"],
+            &actual["2018-03-15"]["gem_version"],
+            &actual["2018-03-15"]["ruby"],
+        ])
+        .unwrap(),
+    );
+}
+
+#[test]
+fn test_sample_10_pivots() {
+    let opts = Options {
+        exact: true,
+        pivots: vec![
+            "ruby,bundler".parse().unwrap(),
+            "platform+ruby".parse().unwrap(),
+        ],
+        ..Default::default()
+    };
+    let actual =
+        serde_json::to_value(file_stats("test/sample_10.log", &opts).unwrap().times).unwrap();
+
+    expect_test::expect![[r#"
+        [
+          {
+            "2.4.1/1.16.1": {
+              "total": 1,
+              "unique": 1
+            }
+          },
+          {
+            "x86_64-linux/2.1.8": {
+              "total": 1,
+              "unique": 1
+            },
+            "x86_64-pc-linux-gnu/2.4.1": {
+              "total": 1,
+              "unique": 1
+            }
+          }
+        ]"#]]
+    .assert_eq(
+        &serde_json::to_string_pretty(&[
+            &actual["2018-04-16"]["ruby+bundler"],
+            &actual["2018-04-16"]["platform+ruby"],
+        ])
+        .unwrap(),
+    );
+    assert_eq!(
+        "ruby".parse::<Pivot>(),
+        Err("pivot \"ruby\" needs at least two fields".to_string())
+    );
+    assert!("ruby,rails".parse::<Pivot>().is_err());
+    assert_eq!(
+        "gem,ruby".parse::<Pivot>(),
+        Err("pivot \"gem,ruby\" can't include gem".to_string())
+    );
+
+    let pivot: Pivot = "ruby_minor,cache_state".parse().unwrap();
+    let rollups = Options {
+        rollups: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        pivot.check(&rollups),
+        Err("pivot ruby_minor+cache_state needs --responses".to_string())
+    );
+    let responses = Options {
+        responses: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        pivot.check(&responses),
+        Err("pivot ruby_minor+cache_state needs --rollups".to_string())
+    );
+    assert_eq!(
+        pivot.check(&Options {
+            responses: true,
+            ..rollups
+        }),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_empty_pivots_are_written() {
+    let opts = Options {
+        exact: true,
+        pivots: vec!["ci,gemstash".parse().unwrap()],
+        ..Default::default()
+    };
+    let actual =
+        serde_json::to_value(file_stats("test/sample_10.log", &opts).unwrap().times).unwrap();
+    assert_eq!(actual["2018-04-16"]["ci+gemstash"], serde_json::json!({}));
+}
+
+#[test]
+fn test_optional_fields_are_written_when_asked_for() {
+    let names = |opts: &Options| {
+        let stats = file_stats("test/sample_10.log", opts).unwrap();
+        let day = serde_json::to_value(stats.times).unwrap()["2018-04-16"].clone();
+        day.as_object().unwrap().keys().cloned().collect::<Vec<_>>()
+    };
+    let mut opts = Options {
+        exact: true,
+        ..Default::default()
+    };
+    let baseline = names(&opts);
+    assert!(
+        !baseline
+            .iter()
+            .any(|name| name == "gem" || name == "response_status")
+    );
+    assert!(!baseline.iter().any(|name| name.ends_with("_major")));
+
+    opts.gems = true;
+    opts.responses = true;
+    opts.rollups = true;
+    let all = names(&opts);
+    for name in [
+        "gem",
+        "gem_version",
+        "response_status",
+        "cache_state",
+        "ruby_minor",
+    ] {
+        assert!(all.iter().any(|n| n == name), "{} is missing", name);
+    }
 }
 
 #[cfg(test)]
@@ -463,13 +1795,677 @@ mod tests {
     fn test_stream_stats() {
         let file = File::open("test/sample_500.log").unwrap();
         let reader = BufReader::new(file);
+        let opts = Options::default();
+        let stats = stream_stats(Box::new(reader), "sample_500.log", &opts);
+        assert_eq!(stats.times.len(), 45);
+        assert_eq!(stats.skipped, Skipped::default());
+    }
+
+    fn malformed_log() -> String {
+        let log = std::fs::read_to_string("test/sample_10.log").unwrap();
+        let good: serde_json::Value = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+        let with = |field: &str, value: Option<&str>| {
+            let mut record = good.clone();
+            match value {
+                Some(value) => record[field] = value.into(),
+                None => {
+                    record.as_object_mut().unwrap().remove(field);
+                }
+            }
+            record.to_string()
+        };
+
+        [
+            good.to_string(),
+            r#"{"timestamp":"2018-04-16 04:59:59","#.to_string(),
+            with("client_ip", Some("139.130.87")),
+            with("timestamp", None),
+            with("timestamp", Some("04:59:59")),
+            with("user_agent", Some("")),
+        ]
+        .join("\n")
+    }
+
+    #[test]
+    fn test_lenient_stream_stats() {
+        let opts = Options {
+            lenient: true,
+            ..Default::default()
+        };
+        let log = malformed_log();
+        let stats = stream_stats(Box::new(log.as_bytes()), "malformed.log", &opts);
+
+        assert_eq!(
+            stats.skipped,
+            Skipped {
+                json: 1,
+                client_ip: 1,
+                timestamp: 2,
+                user_agent: 1,
+                ..Default::default()
+            }
+        );
+        let counters = &stats.times["2018-04-16"];
+        assert_eq!(
+            counters[FieldName::tls_cipher]
+                .values()
+                .next()
+                .unwrap()
+                .total,
+            2
+        );
+        assert_eq!(counters[FieldName::ruby].values().next().unwrap().total, 1);
+    }
+
+    #[test]
+    fn test_dead_letter_stream_stats() {
+        let opts = Options {
+            lenient: true,
+            dead_letter: Some(DeadLetter::memory()),
+            ..Default::default()
+        };
+        let log = malformed_log();
+        stream_stats(Box::new(log.as_bytes()), "malformed.log", &opts);
+
+        let rejected = String::from_utf8(opts.dead_letter.unwrap().take()).unwrap();
+        let rejected: Vec<serde_json::Value> = rejected
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        expect_test::expect![[r#"
+            "malformed.log" 2 "json parse error: EOF while parsing a value at line 2 column 0"
+            "malformed.log" 3 "ipaddr parse error: invalid IP address syntax"
+            "malformed.log" 4 "missing or invalid timestamp"
+            "malformed.log" 5 "missing or invalid timestamp""#]]
+        .assert_eq(
+            &rejected
+                .iter()
+                .map(|r| format!("{} {} {}", r["source"], r["line"], r["error"]))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+        assert_eq!(
+            rejected[0]["record"],
+            r#"{"timestamp":"2018-04-16 04:59:59","#
+        );
+    }
+
+    #[test]
+    fn test_dead_letter_clickhouse() {
+        let log = std::fs::read_to_string("test/sample_500.log").unwrap();
+        let full_names = std::collections::HashMap::new();
+        let context = clickhouse::Context::new(&full_names);
+        let mut w = Vec::new();
+        let error =
+            clickhouse(&mut w, Box::new(log.as_bytes()), "sample_500.log", &context).unwrap_err();
+        assert!(error.to_string().starts_with("unknown full name"));
+
+        let context = context.with_dead_letter(DeadLetter::memory());
+        let mut w = Vec::new();
+        clickhouse(&mut w, Box::new(log.as_bytes()), "sample_500.log", &context).unwrap();
+        assert_eq!(String::from_utf8(w).unwrap().lines().count(), 22);
+
+        let rejected = context.dead_letter.unwrap().take();
+        let rejected = String::from_utf8(rejected).unwrap();
+        let first: serde_json::Value =
+            serde_json::from_str(rejected.lines().next().unwrap()).unwrap();
+        assert_eq!(rejected.lines().count(), 18);
+        assert_eq!(first["source"], "sample_500.log");
+        assert!(
+            first["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("unknown full name: \"aws-sdk-codedeploy-1.3.0\"")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ipaddr parse error: invalid IP address syntax on line 2")]
+    fn test_strict_stream_stats() {
+        let log = malformed_log().replacen("{\"timestamp\":\"2018-04-16 04:59:59\",\n", "", 1);
+        stream_stats(
+            Box::new(log.as_bytes()),
+            "malformed.log",
+            &Options::default(),
+        );
+    }
+
+    fn log_with_invalid_utf8() -> Vec<u8> {
+        let log = std::fs::read("test/sample_10.log").unwrap();
+        [&log[..], b"{\"timestamp\":\"\xff\"}\n"].concat()
+    }
+
+    #[test]
+    fn test_invalid_utf8_lines_are_rejected() {
+        let log = log_with_invalid_utf8();
+        let opts = Options {
+            lenient: true,
+            dead_letter: Some(DeadLetter::memory()),
+            ..Default::default()
+        };
+        let stats = stream_stats(Box::new(log.as_slice()), "invalid.log", &opts);
+        assert_eq!(stats.skipped.encoding, 1);
+        let rejected = String::from_utf8(opts.dead_letter.unwrap().take()).unwrap();
+        let rejected: serde_json::Value = serde_json::from_str(&rejected).unwrap();
+        assert_eq!(rejected["line"], 11);
+        assert_eq!(rejected["error"], "invalid UTF-8");
+        assert_eq!(rejected["record"], "{\"timestamp\":\"\u{fffd}\"}");
+
+        // ClickHouse conversions skip them too, instead of stopping
+        let full_names = std::collections::HashMap::new();
+        let context = clickhouse::Context::new(&full_names).with_dead_letter(DeadLetter::memory());
+        clickhouse(
+            &mut Vec::new(),
+            Box::new(log.as_slice()),
+            "invalid.log",
+            &context,
+        )
+        .unwrap();
+        let rejected = String::from_utf8(context.dead_letter.unwrap().take()).unwrap();
+        let last: serde_json::Value =
+            serde_json::from_str(rejected.lines().last().unwrap()).unwrap();
+        assert_eq!(
+            (&last["line"], &last["error"]),
+            (&11.into(), &"invalid UTF-8".into())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid UTF-8 on line 11")]
+    fn test_strict_invalid_utf8() {
+        let log = log_with_invalid_utf8();
+        stream_stats(Box::new(log.as_slice()), "invalid.log", &Options::default());
+    }
+
+    fn truncated_gzip() -> Vec<u8> {
+        let log = std::fs::read_to_string("test/sample_500.log").unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(log.as_bytes()).unwrap();
+        let mut gzip = gzip.finish().unwrap();
+        gzip.truncate(gzip.len() / 2);
+        gzip
+    }
+
+    #[test]
+    fn test_lenient_truncated_stream_stats() {
+        let opts = Options {
+            lenient: true,
+            ..Default::default()
+        };
+        let gzip = truncated_gzip();
+        let stream = compression::decoder(Box::new(gzip.as_slice())).unwrap();
+        let stats = chunked_stream_stats(stream, "truncated.log.gz", &opts, 1000);
+
+        assert_eq!(stats.skipped.unreadable, 1);
+        // The lines before the truncation are still counted
+        assert!(!stats.times.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "couldn't read truncated.log.gz after line")]
+    fn test_strict_truncated_stream_stats() {
+        let gzip = truncated_gzip();
+        let stream = compression::decoder(Box::new(gzip.as_slice())).unwrap();
+        chunked_stream_stats(stream, "truncated.log.gz", &Options::default(), 1000);
+    }
+
+    #[test]
+    fn test_stream_stats_buckets() {
+        let monthly = Options {
+            bucket: Bucket::Month,
+            ..Default::default()
+        };
+        let times = file_stats("test/sample_500.log", &monthly).unwrap().times;
+        assert_eq!(
+            times.keys().collect::<Vec<_>>(),
+            [
+                "2018-01", "2018-02", "2018-03", "2018-04", "2018-05", "2018-06", "2018-07",
+                "2018-08"
+            ]
+        );
+
+        let hourly = Options {
+            bucket: Bucket::Hour,
+            ..Default::default()
+        };
+        let times = file_stats("test/sample_10.log", &hourly).unwrap().times;
+        assert_eq!(times.keys().collect::<Vec<_>>(), ["2018-04-16 04:00"]);
+    }
+
+    #[test]
+    fn test_approximate_uniques_match_exact() {
+        let exact = Options {
+            exact: true,
+            ..Default::default()
+        };
+        let expected = file_stats("test/sample_500.log", &exact).unwrap().times;
+        let actual = file_stats("test/sample_500.log", &Options::default())
+            .unwrap()
+            .times;
+
+        for (date, counters) in &expected {
+            for (name, values) in counters.histograms() {
+                for (value, counter) in values.entries() {
+                    let approximate = actual[date].get(name.parse().unwrap(), value).unwrap();
+                    assert_eq!(approximate.total, counter.total);
+                    assert_eq!(approximate.unique(), counter.unique(), "{date} {value}");
+                }
+            }
+        }
+
+        // Combining a file with itself doubles the totals, but not the uniques
+        let combined = combine_stats(actual.clone(), actual.clone());
+        for (date, counters) in &actual {
+            for (name, values) in counters.iter() {
+                for (value, counter) in values {
+                    let both = &combined[date][name.parse().unwrap()][value];
+                    assert_eq!(both.total, counter.total * 2);
+                    assert_eq!(both.unique(), counter.unique());
+                }
+            }
+        }
+
+        let json = serde_json::to_value(&combined).unwrap();
+        let counter = &json["2018-03-23"]["tls_cipher"]["ECDHE-RSA-AES128-GCM-SHA256"];
+        assert_eq!(counter["total"], 10);
+        assert!(counter["sketch"]["sparse"].is_string());
+    }
+
+    #[test]
+    fn test_unique_by_splits_shared_ips() {
+        // Two bundler commands from the same IP
+        let log = std::fs::read_to_string("test/sample_10.log").unwrap();
+        let first = log.lines().next().unwrap();
+        let second = first.replace("59dbf8e99fa09c0a", "e710485d04febb1e");
+        let log = [first, &second].join("\n");
+
+        for (unique_by, unique) in [
+            (UniqueBy::Ip, 1),
+            (UniqueBy::IpUserAgent, 2),
+            (UniqueBy::BundlerUid, 2),
+        ] {
+            let opts = Options {
+                exact: true,
+                unique_by,
+                ..Default::default()
+            };
+            let stats = stream_stats(Box::new(log.as_bytes()), "shared.log", &opts);
+            let counter = stats.times["2018-04-16"]
+                .get(FieldName::bundler, "1.16.1")
+                .unwrap();
+            assert_eq!(counter.total, 2);
+            assert_eq!(counter.unique(), unique, "{unique_by}");
+        }
+    }
+
+    #[test]
+    fn test_traffic() {
+        let opts = Options {
+            traffic: true,
+            ..Default::default()
+        };
+        let stats = file_stats("test/sample_500.log", &opts).unwrap();
+
+        // Every counted request has a TLS cipher, so those counts add up to everything
+        let log = std::fs::read_to_string("test/sample_500.log").unwrap();
+        let expected: u64 = log
+            .lines()
+            .map(|line| serde_json::from_str::<request::Request>(line).unwrap())
+            .filter(|r| {
+                opts.rules.counts(
+                    r.method.as_deref(),
+                    &r.shared.request_path,
+                    &r.shared.request_query,
+                )
+            })
+            .map(|r| r.response_bytes.unwrap())
+            .sum();
+        let bytes: u64 = stats
+            .times
+            .values()
+            .flat_map(|counters| counters[FieldName::tls_cipher].values())
+            .map(|counter| counter.traffic.as_ref().unwrap().bytes)
+            .sum();
+        assert_eq!(bytes, expected);
+
+        let json = serde_json::to_value(&stats.times).unwrap();
+        let counter = &json["2018-03-23"]["tls_cipher"]["ECDHE-RSA-AES128-GCM-SHA256"];
+        assert!(counter["bytes"].as_u64().unwrap() > 0);
+        assert!(counter["latency_ms"]["p99"].as_u64().is_some());
+
+        let plain = serde_json::to_value(
+            file_stats("test/sample_500.log", &Options::default())
+                .unwrap()
+                .times,
+        )
+        .unwrap();
+        assert!(
+            plain["2018-03-23"]["tls_cipher"]["ECDHE-RSA-AES128-GCM-SHA256"]["bytes"].is_null()
+        );
+    }
+
+    #[test]
+    fn test_responses() {
+        let opts = Options {
+            responses: true,
+            pivots: vec!["server_datacenter,cache_state".parse().unwrap()],
+            ..Default::default()
+        };
+        let stats = file_stats("test/sample_500.log", &opts).unwrap();
+        let plain = file_stats("test/sample_500.log", &Options::default()).unwrap();
+
+        let sum = |values: &ValueMap| values.values().map(|c| c.total).sum::<usize>();
+        let requests: usize = stats
+            .times
+            .values()
+            .map(|counters| sum(&counters[FieldName::response_class]))
+            .sum();
+        assert_eq!(requests, 498);
+
+        for (date, counters) in &stats.times {
+            let day = &counters.pivots["server_datacenter+cache_state"];
+            assert_eq!(sum(&counters[FieldName::cache_state]), sum(day));
+            // Only the response fields count every request
+            match plain.times.get(date) {
+                Some(plain) => assert_eq!(
+                    sum(&counters[FieldName::tls_cipher]),
+                    sum(&plain[FieldName::tls_cipher])
+                ),
+                None => assert_eq!(sum(&counters[FieldName::tls_cipher]), 0),
+            }
+        }
+        assert!(
+            plain
+                .times
+                .values()
+                .all(|c| c[FieldName::response_status].is_empty())
+        );
+    }
+
+    #[test]
+    fn test_rollups() {
+        let opts = Options {
+            rollups: true,
+            ..Default::default()
+        };
+        let stats = file_stats("test/sample_500.log", &opts).unwrap();
+
+        for counters in stats.times.values() {
+            for (exact, major, minor) in [
+                (
+                    FieldName::ruby,
+                    FieldName::ruby_major,
+                    FieldName::ruby_minor,
+                ),
+                (
+                    FieldName::rubygems,
+                    FieldName::rubygems_major,
+                    FieldName::rubygems_minor,
+                ),
+                (
+                    FieldName::bundler,
+                    FieldName::bundler_major,
+                    FieldName::bundler_minor,
+                ),
+            ] {
+                let sum = |name| counters[name].values().map(|c| c.total).sum::<usize>();
+                assert_eq!(sum(exact), sum(major), "{}", major.as_str());
+                assert_eq!(sum(exact), sum(minor), "{}", minor.as_str());
+                for (&version, counter) in &counters[exact] {
+                    let version = counters.symbols.resolve(version);
+                    let line = version.parse::<Version>().unwrap().release_line(2);
+                    let minor = counters.get(minor, &line).unwrap();
+                    assert!(minor.total >= counter.total);
+                    assert!(minor.unique() >= counter.unique());
+                }
+            }
+        }
+
+        let plain = file_stats("test/sample_500.log", &Options::default()).unwrap();
+        assert!(
+            plain
+                .times
+                .values()
+                .all(|c| c[FieldName::ruby_minor].is_empty())
+        );
+    }
+
+    #[test]
+    fn test_versions_sort_in_output() {
+        let order = |name: &str, keys: &[&str]| -> Vec<String> {
+            let mut symbols = Symbols::default();
+            let values: ValueMap = keys
+                .iter()
+                .map(|&k| (symbols.intern(k), ValueUniqueCounter::new(true)))
+                .collect();
+            let sorted = SortedValues::new(name, &values, &symbols);
+            let json = serde_json::to_string(&sorted).unwrap();
+            let mut sorted: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+            sorted.sort_by_key(|k| json.find(&format!("\"{k}\"")).unwrap());
+            sorted
+        };
+
+        assert_eq!(
+            order("bundler", &["2.10.0", "junk", "2.9.0", "2.9.0.pre", "2.9"]),
+            ["2.9.0.pre", "2.9", "2.9.0", "2.10.0", "junk"]
+        );
+        assert_eq!(order("ci", &["travis", "circle"]), ["circle", "travis"]);
+        assert_eq!(
+            order("gem_version", &["rack/2.10.0", "rails/1.0", "rack/2.9.0"]),
+            ["rack/2.9.0", "rack/2.10.0", "rails/1.0"]
+        );
+        assert_eq!(
+            order(
+                "ci+ruby",
+                &["travis/3.10.0", "circle/3.9.0", "travis/3.9.0"]
+            ),
+            ["circle/3.9.0", "travis/3.9.0", "travis/3.10.0"]
+        );
+    }
+
+    #[test]
+    fn test_limit_pivot_spellings() {
+        for spec in ["ruby,bundler=5", "bundler+ruby=5", "bundler,ruby=5"] {
+            let mut limits = Limits::default();
+            limits.add(spec).unwrap();
+            assert_eq!(limits.get("ruby+bundler"), Some(5), "{spec}");
+            assert_eq!(limits.get("ruby"), None, "{spec}");
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut limits = Limits::default();
+        limits.add("platform=2").unwrap();
+        limits.add("ruby+bundler=1").unwrap();
+        limits.add("5").unwrap();
+        assert!(limits.add("platfrom=2").is_err());
+        assert!(limits.add("ruby+bundlr=2").is_err());
+        assert!(limits.add("platform=many").is_err());
+
+        let opts = Options {
+            exact: true,
+            pivots: vec!["ruby,bundler".parse().unwrap()],
+            ..Default::default()
+        };
+        let first = file_stats("test/sample_500.log", &opts).unwrap();
+        let second = file_stats("test/sample_10.log", &opts).unwrap();
+        let unlimited = first.clone().combine(second.clone());
+
+        // Limiting each side first and then again after combining gives the same totals
+        let mut limited = unlimited.clone();
+        limited.limit(&limits);
+        let (mut a, mut b) = (first, second);
+        a.limit(&limits);
+        b.limit(&limits);
+        let mut relimited = a.combine(b);
+        relimited.limit(&limits);
+
+        for (date, counters) in &unlimited.times {
+            for (name, values) in counters.iter() {
+                let limit = limits.get(name).unwrap();
+                let sum = |values: &ValueMap| values.values().map(|c| c.total).sum::<usize>();
+                for stats in [&limited, &relimited] {
+                    let capped = stats.times[date]
+                        .iter()
+                        .find(|(n, _)| *n == name)
+                        .unwrap()
+                        .1;
+                    assert_eq!(sum(capped), sum(values), "{date} {name}");
+                    assert!(capped.len() <= limit + 1, "{date} {name}");
+                    let other = stats.times[date].symbols.get(OTHER);
+                    assert_eq!(
+                        other.is_some_and(|other| capped.contains_key(&other)),
+                        values.len() > limit
+                    );
+                }
+            }
+        }
+
+        // The most common values are kept as they were
+        let day = &unlimited.times["2018-03-23"];
+        let mut top: Vec<_> = day[FieldName::ruby]
+            .iter()
+            .map(|(&value, counter)| (day.symbols.resolve(value), counter))
+            .collect();
+        top.sort_by(|a, b| b.1.total.cmp(&a.1.total).then_with(|| a.0.cmp(b.0)));
+        let capped = &limited.times["2018-03-23"];
+        for (value, counter) in top.iter().take(5) {
+            let kept = capped.get(FieldName::ruby, value).unwrap();
+            assert_eq!(kept.total, counter.total);
+            assert_eq!(kept.unique(), counter.unique());
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let opts = Options {
+            filter: Some(filter::Filter::for_stats(r#"client_country == "Australia""#).unwrap()),
+            ..Default::default()
+        };
+        let stats = file_stats("test/sample_10.log", &opts).unwrap();
+        let day = &stats.times["2018-04-16"];
+        let countries: Vec<_> = day[FieldName::client_country]
+            .keys()
+            .map(|&country| day.symbols.resolve(country))
+            .collect();
+        assert_eq!(countries, ["Australia"]);
+    }
+
+    #[test]
+    fn test_read_stats() {
         let opts = Options {
-            verbose: false,
-            unknown: false,
-            paths: vec![],
+            traffic: true,
+            pivots: vec!["ruby,bundler".parse().unwrap()],
+            ..Default::default()
+        };
+        let log = std::fs::read_to_string("test/sample_500.log").unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        let (first, second) = lines.split_at(lines.len() / 2);
+        let half = |lines: &[&str]| {
+            let log = lines.join("\n");
+            stream_stats(Box::new(log.as_bytes()), "half.log", &opts)
+        };
+
+        // One half as `kirby` prints it, and the other as the Lambda uploads it
+        let printed = serde_json::json!({
+            "ran_at": "2018-04-17T00:00:00Z",
+            "stats": half(first).times,
+            "files": ["first.log"],
+            "skipped": half(first).skipped,
+        });
+        let uploaded = serde_json::to_value(half(second).times).unwrap();
+        let mut merged = read_stats(printed.to_string().as_bytes())
+            .unwrap()
+            .combine(read_stats(uploaded.to_string().as_bytes()).unwrap());
+
+        let whole = file_stats("test/sample_500.log", &opts).unwrap();
+        assert_eq!(
+            serde_json::to_value(&merged.times).unwrap(),
+            serde_json::to_value(&whole.times).unwrap()
+        );
+
+        let monthly = Options {
+            bucket: Bucket::Month,
+            ..opts
+        };
+        merged.rebucket(Bucket::Month).unwrap();
+        assert_eq!(
+            serde_json::to_value(&merged.times).unwrap(),
+            serde_json::to_value(file_stats("test/sample_500.log", &monthly).unwrap().times)
+                .unwrap()
+        );
+
+        // Weeks can run from one month into the next
+        let weekly = Options {
+            bucket: Bucket::Week,
+            ..Default::default()
+        };
+        let mut weekly = file_stats("test/sample_500.log", &weekly).unwrap();
+        assert_eq!(
+            weekly.rebucket(Bucket::Month),
+            Err("can't turn week buckets into month buckets".to_string())
+        );
+
+        let exact = Options {
+            exact: true,
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&file_stats("test/sample_10.log", &exact).unwrap().times);
+        let error = read_stats(json.unwrap().as_bytes()).unwrap_err();
+        // Which is also what stats from before sketches look like
+        assert!(error.to_string().contains("--exact"), "{error}");
+        assert!(error.to_string().contains("before sketches"), "{error}");
+    }
+
+    #[test]
+    fn test_chunk_size_doesnt_change_results() {
+        let log = std::fs::read_to_string("test/sample_500.log").unwrap() + &malformed_log();
+        let opts = || Options {
+            lenient: true,
+            dead_letter: Some(DeadLetter::memory()),
+            ..Default::default()
+        };
+        let stats = |chunk_size| {
+            let opts = opts();
+            let stats =
+                chunked_stream_stats(Box::new(log.as_bytes()), "test.log", &opts, chunk_size);
+            let rejected = opts.dead_letter.unwrap().take();
+            (
+                serde_json::to_string(&stats.times).unwrap(),
+                stats.skipped,
+                rejected,
+            )
+        };
+        let whole = stats(10 << 20);
+        assert_eq!(whole.1.json, 1);
+        for chunk_size in [1, 1000, 64 << 10] {
+            assert!(stats(chunk_size) == whole, "{chunk_size}");
+        }
+
+        // Leave out the full names so that some downloads are rejected too
+        let log = std::fs::read_to_string("test/sample_500.log").unwrap() + &malformed_log();
+        let full_names = std::collections::HashMap::new();
+        let context = clickhouse::Context::new(&full_names).with_dead_letter(DeadLetter::memory());
+        let rows = |chunk_size| {
+            let mut rows = Vec::new();
+            chunked_clickhouse(
+                &mut rows,
+                Box::new(log.as_bytes()),
+                "test.log",
+                &context,
+                chunk_size,
+            )
+            .unwrap();
+            (rows, context.dead_letter.as_ref().unwrap().take())
         };
-        let times = stream_stats(Box::new(reader), &opts);
-        assert_eq!(times.len(), 45);
+        let whole = rows(10 << 20);
+        assert!(!whole.0.is_empty() && !whole.1.is_empty());
+        let rejected = String::from_utf8(whole.1.clone()).unwrap();
+        assert!(rejected.contains("EOF while parsing"), "{rejected}");
+        for chunk_size in [1, 1000, 64 << 10] {
+            assert!(rows(chunk_size) == whole, "{chunk_size}");
+        }
     }
 
     #[bench]
@@ -479,14 +2475,32 @@ mod tests {
             .unwrap()
             .read_to_end(&mut logs)
             .unwrap();
-        let opts = Options {
-            verbose: false,
-            unknown: false,
-            paths: vec![],
-        };
+        let opts = Options::default();
         b.iter(|| {
             let reader = Box::new(BufReader::new(logs.as_slice()));
-            stream_stats(reader, &opts);
+            stream_stats(reader, "sample_500.log", &opts);
         });
     }
+
+    #[bench]
+    fn bench_combine_stats_sample_500(b: &mut Bencher) {
+        let opts = Options::default();
+        let stats = file_stats("test/sample_500.log", &opts).unwrap();
+        b.iter(|| stats.clone().combine(stats.clone()));
+    }
+
+    fn bench_parse_sample_500(b: &mut Bencher, parse: fn(&str) -> Option<request::Request>) {
+        let log = std::fs::read_to_string("test/sample_500.log").unwrap();
+        b.iter(|| log.lines().filter_map(parse).count());
+    }
+
+    #[bench]
+    fn bench_parse_sample_500_serde(b: &mut Bencher) {
+        bench_parse_sample_500(b, |line| serde_json::from_str(line).ok());
+    }
+
+    #[bench]
+    fn bench_parse_sample_500_scan(b: &mut Bencher) {
+        bench_parse_sample_500(b, |line| request::Request::from_line(line).ok());
+    }
 }
@@ -0,0 +1,278 @@
+//! Writing stats out as JSON, or as flat rows of `(date, field, value, total, unique)` for
+//! loading into spreadsheets, DuckDB, or a warehouse.
+
+use std::fmt;
+use std::io::{Error, Result, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use crate::bucket::Bucket;
+use crate::{Skipped, TimeMap};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// One nested JSON object, with sketches so that stats can be merged later
+    #[default]
+    Json,
+    /// One JSON object per row
+    Ndjson,
+    /// Comma-separated rows, with a header
+    Csv,
+    /// A Parquet file, compressed with zstd
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::Ndjson),
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(format!("unknown output format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        })
+    }
+}
+
+/// The nested JSON that `kirby` prints, with details of the run next to the stats. It's
+/// written straight from the stats, since a `serde_json::Value` would sort versions as
+/// strings.
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<Bucket>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<&'a str>,
+    pub files: &'a [String],
+    pub ran_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<&'a Skipped>,
+    pub stats: &'a TimeMap,
+}
+
+pub fn write_json<W: Write>(mut w: W, report: &Report) -> Result<()> {
+    serde_json::to_writer(&mut w, report)?;
+    w.write_all(b"\n")?;
+    w.flush()
+}
+
+/// The count of one value of one field in one time bucket.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Row<'a> {
+    pub date: &'a str,
+    pub field: &'a str,
+    pub value: &'a str,
+    pub total: usize,
+    pub unique: usize,
+}
+
+/// Flattens stats into rows.
+pub fn rows(times: &TimeMap) -> impl Iterator<Item = Row<'_>> {
+    times.iter().flat_map(|(date, counters)| {
+        counters.histograms().flat_map(move |(field, values)| {
+            values
+                .entries()
+                .into_iter()
+                .map(move |(value, counter)| Row {
+                    date,
+                    field,
+                    value,
+                    total: counter.total,
+                    unique: counter.unique(),
+                })
+        })
+    })
+}
+
+pub fn write_ndjson<W: Write>(mut w: W, times: &TimeMap) -> Result<()> {
+    for row in rows(times) {
+        serde_json::to_writer(&mut w, &row)?;
+        w.write_all(b"\n")?;
+    }
+    w.flush()
+}
+
+pub fn write_csv<W: Write>(w: W, times: &TimeMap) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(w);
+    for row in rows(times) {
+        writer.serialize(row)?;
+    }
+    writer.flush()
+}
+
+const PARQUET_SCHEMA: &str = "
+    message stats {
+        required binary date (UTF8);
+        required binary field (UTF8);
+        required binary value (UTF8);
+        required int64 total;
+        required int64 unique;
+    }
+";
+
+pub fn write_parquet<W: Write + Send>(w: W, times: &TimeMap) -> Result<()> {
+    let rows: Vec<Row> = rows(times).collect();
+    let strings = [
+        rows.iter()
+            .map(|r| ByteArray::from(r.date))
+            .collect::<Vec<_>>(),
+        rows.iter().map(|r| ByteArray::from(r.field)).collect(),
+        rows.iter().map(|r| ByteArray::from(r.value)).collect(),
+    ];
+    let numbers = [
+        rows.iter().map(|r| r.total as i64).collect::<Vec<_>>(),
+        rows.iter().map(|r| r.unique as i64).collect(),
+    ];
+
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA).map_err(Error::other)?);
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+    let mut writer =
+        SerializedFileWriter::new(w, schema, Arc::new(properties)).map_err(Error::other)?;
+    let mut row_group = writer.next_row_group().map_err(Error::other)?;
+    for column in strings {
+        let mut writer = row_group.next_column().map_err(Error::other)?.unwrap();
+        writer
+            .typed::<ByteArrayType>()
+            .write_batch(&column, None, None)
+            .map_err(Error::other)?;
+        writer.close().map_err(Error::other)?;
+    }
+    for column in numbers {
+        let mut writer = row_group.next_column().map_err(Error::other)?.unwrap();
+        writer
+            .typed::<Int64Type>()
+            .write_batch(&column, None, None)
+            .map_err(Error::other)?;
+        writer.close().map_err(Error::other)?;
+    }
+    row_group.close().map_err(Error::other)?;
+    writer.close().map_err(Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Options, file_stats};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    #[test]
+    fn test_rows() {
+        let opts = Options {
+            pivots: vec!["ruby,bundler".parse().unwrap()],
+            ..Default::default()
+        };
+        let stats = file_stats("test/sample_10.log", &opts).unwrap();
+        let rows: Vec<Row> = rows(&stats.times).collect();
+
+        let counters = &stats.times["2018-04-16"];
+        let expected: usize = counters.iter().map(|(_, values)| values.len()).sum();
+        assert_eq!(rows.len(), expected);
+        assert_eq!(
+            rows.iter().find(|r| r.field == "ruby+bundler").unwrap(),
+            &Row {
+                date: "2018-04-16",
+                field: "ruby+bundler",
+                value: "2.4.1/1.16.1",
+                total: 1,
+                unique: 1,
+            }
+        );
+
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &stats.times).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("date,field,value,total,unique"));
+        assert_eq!(lines.count(), rows.len());
+
+        let mut ndjson = Vec::new();
+        write_ndjson(&mut ndjson, &stats.times).unwrap();
+        let first: serde_json::Value = serde_json::from_str(
+            std::str::from_utf8(&ndjson)
+                .unwrap()
+                .lines()
+                .next()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(first, serde_json::to_value(&rows[0]).unwrap());
+
+        let mut parquet = Vec::new();
+        write_parquet(&mut parquet, &stats.times).unwrap();
+        let reader = SerializedFileReader::new(bytes::Bytes::from(parquet)).unwrap();
+        let read: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(read.len(), rows.len());
+        for (read, row) in read.iter().zip(&rows) {
+            assert_eq!(read.get_string(0).unwrap(), row.date);
+            assert_eq!(read.get_string(1).unwrap(), row.field);
+            assert_eq!(read.get_string(2).unwrap(), row.value);
+            assert_eq!(read.get_long(3).unwrap(), row.total as i64);
+            assert_eq!(read.get_long(4).unwrap(), row.unique as i64);
+        }
+    }
+
+    #[test]
+    fn test_write_json_keeps_version_order() {
+        let opts = Options {
+            exact: true,
+            bucket: Bucket::Month,
+            ..Default::default()
+        };
+        let stats = file_stats("test/sample_500.log", &opts).unwrap();
+        let mut json = Vec::new();
+        let report = Report {
+            bucket: stats.bucket,
+            database: None,
+            files: &["test/sample_500.log".to_string()],
+            ran_at: "2018-04-17T00:00:00Z".to_string(),
+            skipped: Some(&stats.skipped),
+            stats: &stats.times,
+        };
+        write_json(&mut json, &report).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with(r#"{"bucket":"month","files":["test/sample_500.log"],"#));
+
+        // Exact counts have no nested objects, so the histogram ends at the first `}}`
+        let start = json.find(r#""rubygems":{"#).unwrap();
+        let rubygems = &json[start..start + json[start..].find("}}").unwrap()];
+        let versions: Vec<&str> = regex::Regex::new(r#""([^"]+)":\{"total""#)
+            .unwrap()
+            .captures_iter(rubygems)
+            .map(|c| c.get(1).unwrap().as_str())
+            .collect();
+        assert_eq!(
+            versions,
+            [
+                "1.8.11", "2.0.14.1", "2.4.4", "2.4.8", "2.5.1", "2.6.3", "2.6.7", "2.6.10",
+                "2.6.11",
+            ]
+        );
+    }
+}
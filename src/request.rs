@@ -9,7 +9,7 @@ fn default_ip<'a>() -> Cow<'a, str> {
 }
 
 #[derive(Serialize, Debug)]
-pub struct ResponseStatus(u16);
+pub struct ResponseStatus(pub(crate) u16);
 
 impl ResponseStatus {
     pub fn is_success(&self) -> bool {
@@ -19,6 +19,24 @@ impl ResponseStatus {
     pub fn not_modified(&self) -> bool {
         self.0 == 304
     }
+
+    /// The class of the status, like `2xx` or `4xx`.
+    pub fn class(&self) -> &'static str {
+        match self.0 {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            500..=599 => "5xx",
+            _ => "other",
+        }
+    }
+}
+
+impl fmt::Display for ResponseStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
 }
 
 impl<'de> Deserialize<'de> for ResponseStatus {
@@ -59,7 +77,7 @@ impl<'de> Deserialize<'de> for ResponseStatus {
 
 #[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct Shared<'a> {
-    #[serde(borrow)]
+    #[serde(borrow, default)]
     pub timestamp: Cow<'a, str>,
     #[serde(borrow)]
     pub request_path: Cow<'a, str>,
@@ -138,6 +156,47 @@ pub struct Request<'a> {
     #[serde(borrow)]
     #[serde(default = "default_ip")]
     pub client_ip: Cow<'a, str>,
+
+    #[serde(
+        borrow,
+        rename = "request",
+        deserialize_with = "empty_string_is_none",
+        default
+    )]
+    pub method: Option<Cow<'a, str>>,
+    #[serde(borrow, deserialize_with = "empty_string_is_none", default)]
+    pub request_host: Option<Cow<'a, str>>,
+
+    #[serde(default)]
+    pub response_status: Option<ResponseStatus>,
+    #[serde(borrow, deserialize_with = "empty_string_is_none", default)]
+    pub response_cache: Option<Cow<'a, str>>,
+    #[serde(borrow, deserialize_with = "empty_string_is_none", default)]
+    pub cache_state: Option<Cow<'a, str>>,
+    #[serde(deserialize_with = "number_or_string", default)]
+    pub response_bytes: Option<u64>,
+    #[serde(deserialize_with = "number_or_string", default)]
+    pub time_elapsed: Option<u64>,
+
+    #[serde(borrow, deserialize_with = "empty_string_is_none", default)]
+    pub client_continent: Option<Cow<'a, str>>,
+    #[serde(borrow, deserialize_with = "empty_string_is_none", default)]
+    pub client_country: Option<Cow<'a, str>>,
+    #[serde(borrow, deserialize_with = "empty_string_is_none", default)]
+    pub server_region: Option<Cow<'a, str>>,
+    #[serde(borrow, deserialize_with = "empty_string_is_none", default)]
+    pub server_datacenter: Option<Cow<'a, str>>,
+}
+
+impl<'a> Request<'a> {
+    /// Reads a request from a log line, scanning for just the fields we count when the
+    /// line is simple enough, and deserializing the whole line when it isn't.
+    pub fn from_line(line: &'a str) -> serde_json::Result<Self> {
+        match crate::scan::request(line) {
+            Some(request) => Ok(request),
+            None => serde_json::from_str(line),
+        }
+    }
 }
 
 fn empty_string_is_none<'a, D>(deserializer: D) -> Result<Option<Cow<'a, str>>, D::Error>
@@ -151,3 +210,24 @@ where
         Ok(s)
     }
 }
+
+/// Reads numbers that may have been logged as strings, and ignores ones that aren't
+/// numbers at all, since they're only used for optional stats.
+fn number_or_string<'a, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'a>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString<'a> {
+        Number(u64),
+        String(#[serde(borrow)] Cow<'a, str>),
+        Other(serde::de::IgnoredAny),
+    }
+
+    Ok(match Deserialize::deserialize(deserializer)? {
+        NumberOrString::Number(n) => Some(n),
+        NumberOrString::String(s) => s.parse().ok(),
+        NumberOrString::Other(_) => None,
+    })
+}
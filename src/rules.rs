@@ -0,0 +1,125 @@
+//! Rules for which requests are counted, so that each `bundle install` or `gem install`
+//! is counted once. The first rule that matches a request decides.
+
+use std::fs;
+use std::io;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+static DEFAULT_RULES: LazyLock<Rules> = LazyLock::new(|| {
+    serde_json::from_str(include_str!("rules.json")).expect("invalid default rules")
+});
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Query {
+    Empty,
+    Present,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(deserialize_with = "deserialize_regex")]
+    path: Regex,
+    #[serde(default)]
+    query: Option<Query>,
+    #[serde(default)]
+    method: Option<String>,
+    count: bool,
+    #[serde(default, rename = "comment")]
+    _comment: Option<String>,
+}
+
+impl Rule {
+    fn matches(&self, method: Option<&str>, path: &str, query: &str) -> bool {
+        let query_matches = match self.query {
+            None => true,
+            Some(Query::Empty) => query.is_empty(),
+            Some(Query::Present) => !query.is_empty(),
+        };
+        let method_matches = match &self.method {
+            None => true,
+            Some(expected) => method.is_some_and(|m| m.eq_ignore_ascii_case(expected)),
+        };
+        query_matches && method_matches && self.path.is_match(path)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(transparent)]
+pub struct Rules(Vec<Rule>);
+
+impl Default for Rules {
+    fn default() -> Self {
+        DEFAULT_RULES.clone()
+    }
+}
+
+impl Rules {
+    /// Reads rules from a JSON file.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Whether a request should be counted, according to the first rule that matches it.
+    pub fn counts(&self, method: Option<&str>, path: &str, query: &str) -> bool {
+        self.0
+            .iter()
+            .find(|rule| rule.matches(method, path, query))
+            .is_some_and(|rule| rule.count)
+    }
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_rules() {
+        let rules = Rules::default();
+        let counts = |method, path, query| rules.counts(Some(method), path, query);
+
+        assert!(counts("GET", "/api/v1/dependencies", ""));
+        assert!(counts("HEAD", "/api/v1/dependencies", ""));
+        assert!(!counts("GET", "/api/v1/dependencies", "gems=rack,rake"));
+        assert!(counts("GET", "/versions", ""));
+        assert!(!counts("GET", "/info/rack", ""));
+        assert!(counts("GET", "/specs.4.8.gz", ""));
+        assert!(counts("GET", "/latest_specs.4.8.gz", ""));
+        assert!(counts("GET", "/prerelease_specs.4.8.gz", ""));
+        assert!(!counts("GET", "/gems/rack-2.0.5.gem", ""));
+        assert!(!counts(
+            "GET",
+            "/quick/Marshal.4.8/rack-2.0.5.gemspec.rz",
+            ""
+        ));
+    }
+
+    #[test]
+    fn test_method_and_query_conditions() {
+        let rules: Rules = serde_json::from_str(
+            r#"[
+              { "path": "^/versions$", "method": "get", "query": "present", "count": false },
+              { "path": "^/versions$", "method": "GET", "count": true }
+            ]"#,
+        )
+        .unwrap();
+
+        assert!(rules.counts(Some("GET"), "/versions", ""));
+        assert!(!rules.counts(Some("GET"), "/versions", "x=1"));
+        assert!(!rules.counts(Some("HEAD"), "/versions", ""));
+        assert!(!rules.counts(None, "/versions", ""));
+
+        assert!(serde_json::from_str::<Rules>(r#"[{ "path": "(", "count": true }]"#).is_err());
+        assert!(serde_json::from_str::<Rules>(r#"[{ "path": "/", "cuont": true }]"#).is_err());
+    }
+}
@@ -1,10 +1,9 @@
 use std::{
-    io::{BufRead, BufReader, Cursor},
+    io::{BufRead, Cursor},
     str::FromStr,
 };
 
 use aws_sdk_s3::Client;
-use flate2::bufread::GzDecoder;
 use lambda_runtime::tracing::info;
 
 pub async fn read_object(client: &Client, bucket_name: &str, key: &str) -> Box<dyn BufRead> {
@@ -30,11 +29,8 @@ pub async fn read_object(client: &Client, bucket_name: &str, key: &str) -> Box<d
         key
     );
 
-    if key.ends_with("gz") {
-        Box::new(BufReader::new(GzDecoder::new(Cursor::new(bytes))))
-    } else {
-        Box::new(BufReader::new(Cursor::new(bytes)))
-    }
+    crate::compression::decoder(Box::new(Cursor::new(bytes)))
+        .unwrap_or_else(|e| panic!("Couldn't decompress {}: {}", key, e))
 }
 
 pub enum S3EventType {
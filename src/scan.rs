@@ -0,0 +1,363 @@
+//! A fast path for reading the fields kirby counts out of a log line without serde, which
+//! returns `None` for anything unusual so that serde can parse the line instead.
+
+use std::borrow::Cow;
+
+use crate::request::{Request, ResponseStatus, Shared};
+
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    /// A string with nothing to unescape
+    Str(&'a str),
+    /// A string with escapes in it, which serde has to read
+    Escaped,
+    Number(&'a str),
+    Bool,
+    Null,
+}
+
+// Where each field we keep is stored while scanning
+const TIMESTAMP: usize = 0;
+const REQUEST_PATH: usize = 1;
+const REQUEST_QUERY: usize = 2;
+const USER_AGENT: usize = 3;
+const TLS_CIPHER: usize = 4;
+const CLIENT_IP: usize = 5;
+const METHOD: usize = 6;
+const RESPONSE_STATUS: usize = 7;
+const RESPONSE_CACHE: usize = 8;
+const CACHE_STATE: usize = 9;
+const RESPONSE_BYTES: usize = 10;
+const TIME_ELAPSED: usize = 11;
+const CLIENT_CONTINENT: usize = 12;
+const CLIENT_COUNTRY: usize = 13;
+const SERVER_REGION: usize = 14;
+const SERVER_DATACENTER: usize = 15;
+const REQUEST_HOST: usize = 16;
+const FIELDS: usize = 17;
+
+fn field(key: &str) -> Option<usize> {
+    Some(match key {
+        "timestamp" => TIMESTAMP,
+        "request_path" => REQUEST_PATH,
+        "request_query" => REQUEST_QUERY,
+        "user_agent" => USER_AGENT,
+        "tls_cipher" => TLS_CIPHER,
+        "client_ip" => CLIENT_IP,
+        "request" => METHOD,
+        "response_status" => RESPONSE_STATUS,
+        "response_cache" => RESPONSE_CACHE,
+        "cache_state" => CACHE_STATE,
+        "response_bytes" => RESPONSE_BYTES,
+        "time_elapsed" => TIME_ELAPSED,
+        "client_continent" => CLIENT_CONTINENT,
+        "client_country" => CLIENT_COUNTRY,
+        "server_region" => SERVER_REGION,
+        "server_datacenter" => SERVER_DATACENTER,
+        "request_host" => REQUEST_HOST,
+        _ => return None,
+    })
+}
+
+/// Reads a request out of a log line, or returns `None` if the line needs serde.
+pub fn request(line: &str) -> Option<Request<'_>> {
+    let mut scanner = Scanner { line, pos: 0 };
+    let mut values: [Option<Value>; FIELDS] = [None; FIELDS];
+
+    scanner.skip_whitespace();
+    scanner.expect(b'{')?;
+    scanner.skip_whitespace();
+    if !scanner.eat(b'}') {
+        loop {
+            scanner.skip_whitespace();
+            let key = match scanner.value()? {
+                Value::Str(key) => key,
+                _ => return None,
+            };
+            scanner.skip_whitespace();
+            scanner.expect(b':')?;
+            scanner.skip_whitespace();
+            let value = scanner.value()?;
+            match field(key) {
+                Some(i) if values[i].is_some() => return None,
+                Some(i) => values[i] = Some(value),
+                // serde ignores fields we don't keep, even repeated ones
+                None => {}
+            }
+            scanner.skip_whitespace();
+            if scanner.eat(b'}') {
+                break;
+            }
+            scanner.expect(b',')?;
+        }
+    }
+    scanner.skip_whitespace();
+    if scanner.pos != line.len() {
+        return None;
+    }
+
+    Some(Request {
+        shared: Shared {
+            timestamp: string_or(values[TIMESTAMP], "")?,
+            request_path: required_string(values[REQUEST_PATH])?,
+            request_query: required_string(values[REQUEST_QUERY])?,
+            user_agent: required_string(values[USER_AGENT])?,
+            tls_cipher: required_string(values[TLS_CIPHER])?,
+        },
+        client_ip: string_or(values[CLIENT_IP], "0.0.0.0")?,
+        method: optional_string(values[METHOD])?,
+        request_host: optional_string(values[REQUEST_HOST])?,
+        response_status: response_status(values[RESPONSE_STATUS])?,
+        response_cache: optional_string(values[RESPONSE_CACHE])?,
+        cache_state: optional_string(values[CACHE_STATE])?,
+        response_bytes: number_or_string(values[RESPONSE_BYTES])?,
+        time_elapsed: number_or_string(values[TIME_ELAPSED])?,
+        client_continent: optional_string(values[CLIENT_CONTINENT])?,
+        client_country: optional_string(values[CLIENT_COUNTRY])?,
+        server_region: optional_string(values[SERVER_REGION])?,
+        server_datacenter: optional_string(values[SERVER_DATACENTER])?,
+    })
+}
+
+// Each of these matches what the serde attributes on `Request` do with a value, and
+// returns `None` for anything that serde would handle differently or reject.
+
+fn required_string(value: Option<Value>) -> Option<Cow<str>> {
+    match value? {
+        Value::Str(s) => Some(Cow::Borrowed(s)),
+        _ => None,
+    }
+}
+
+fn string_or<'a>(value: Option<Value<'a>>, default: &'static str) -> Option<Cow<'a, str>> {
+    match value {
+        None => Some(Cow::Borrowed(default)),
+        value => required_string(value),
+    }
+}
+
+fn optional_string(value: Option<Value>) -> Option<Option<Cow<str>>> {
+    match value {
+        None | Some(Value::Null) | Some(Value::Str("")) => Some(None),
+        Some(Value::Str(s)) => Some(Some(Cow::Borrowed(s))),
+        _ => None,
+    }
+}
+
+fn response_status(value: Option<Value>) -> Option<Option<ResponseStatus>> {
+    let digits = match value {
+        None | Some(Value::Null) => return Some(None),
+        Some(Value::Str(s)) | Some(Value::Number(s)) => s,
+        _ => return None,
+    };
+    let status: u64 = digits.parse().ok()?;
+    Some(Some(ResponseStatus(status.try_into().ok()?)))
+}
+
+fn number_or_string(value: Option<Value>) -> Option<Option<u64>> {
+    match value {
+        None | Some(Value::Null) | Some(Value::Bool) => Some(None),
+        // serde reads `-0` as zero, so leave negative numbers to it
+        Some(Value::Number(n)) if n.starts_with('-') => None,
+        Some(Value::Number(n)) | Some(Value::Str(n)) => Some(n.parse().ok()),
+        Some(Value::Escaped) => None,
+    }
+}
+
+struct Scanner<'a> {
+    line: &'a str,
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.line.as_bytes().get(self.pos).copied()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        self.eat(byte).then_some(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn literal(&mut self, literal: &str, value: Value<'a>) -> Option<Value<'a>> {
+        if self.line[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Any value except objects and arrays, which Fastly doesn't log.
+    fn value(&mut self) -> Option<Value<'a>> {
+        match self.peek()? {
+            b'"' => self.string(),
+            b'-' | b'0'..=b'9' => self.number(),
+            b't' => self.literal("true", Value::Bool),
+            b'f' => self.literal("false", Value::Bool),
+            b'n' => self.literal("null", Value::Null),
+            _ => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<Value<'a>> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        let mut escaped = false;
+        loop {
+            // Skip plain characters in one go, stopping at anything that needs a look
+            let bytes = &self.line.as_bytes()[self.pos..];
+            self.pos += bytes
+                .iter()
+                .position(|&b| b == b'"' || b == b'\\' || b < 0x20)
+                .unwrap_or(bytes.len());
+            match self.peek()? {
+                b'"' => break,
+                b'\\' => {
+                    escaped = true;
+                    self.pos += 1;
+                    match self.peek()? {
+                        b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => self.pos += 1,
+                        b'u' => {
+                            let hex = self.line.as_bytes().get(self.pos + 1..self.pos + 5)?;
+                            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                                return None;
+                            }
+                            self.pos += 5;
+                        }
+                        _ => return None,
+                    }
+                }
+                0x00..=0x1f => return None,
+                _ => self.pos += 1,
+            }
+        }
+        let s = &self.line[start..self.pos];
+        self.pos += 1;
+        Some(if escaped {
+            Value::Escaped
+        } else {
+            Value::Str(s)
+        })
+    }
+
+    fn number(&mut self) -> Option<Value<'a>> {
+        let start = self.pos;
+        self.eat(b'-');
+        if self.eat(b'0') {
+            if self.peek().is_some_and(|b| b.is_ascii_digit()) {
+                return None;
+            }
+        } else if self.skip_digits() == 0 {
+            return None;
+        }
+        if self.eat(b'.') && self.skip_digits() == 0 {
+            return None;
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            if self.skip_digits() == 0 {
+                return None;
+            }
+        }
+        Some(Value::Number(&self.line[start..self.pos]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serde(line: &str) -> Option<String> {
+        serde_json::from_str::<Request>(line)
+            .ok()
+            .map(|r| format!("{:?}", r))
+    }
+
+    fn scan(line: &str) -> Option<String> {
+        request(line).map(|r| format!("{:?}", r))
+    }
+
+    #[test]
+    fn test_scan_matches_serde_on_samples() {
+        for path in [
+            "test/sample_10.log",
+            "test/sample_10_dups.log",
+            "test/sample_500.log",
+        ] {
+            let log = std::fs::read_to_string(path).unwrap();
+            for line in log.lines() {
+                let scanned = scan(line);
+                assert!(scanned.is_some(), "{line}");
+                assert_eq!(scanned, serde(line), "{line}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_falls_back_or_matches_serde() {
+        let base = r#""request_path":"/versions","request_query":"","user_agent":"bundler/2.5.3","tls_cipher":"""#;
+        let line = |rest: &str| format!("{{{base}{rest}}}");
+
+        // Lines the scanner reads itself, and has to read just like serde
+        for rest in [
+            "",
+            r#","timestamp":"2018-04-16 04:59:59","client_ip":"::1""#,
+            r#","request":"","response_cache":null,"cache_state":"HIT""#,
+            r#","response_status":200,"response_bytes":"1855","time_elapsed":1.5"#,
+            r#","response_status":"304","response_bytes":1e3,"time_elapsed":true"#,
+            r#","response_bytes":"big","time_elapsed":99999999999999999999"#,
+            r#","client_city":"Montréal","client_latitude":-33.867"#,
+            r#","http2":false,"http2":true,"client_region":"a\"b""#,
+            r#" , "server_region" : "APAC" , "server_datacenter":"SYD"  "#,
+        ] {
+            let line = line(rest);
+            assert!(scan(&line).is_some(), "{line}");
+            assert_eq!(scan(&line), serde(&line), "{line}");
+        }
+
+        // Lines that need serde, to unescape them or to reject them
+        let unusual = [
+            line(r#","user_agent":"bundler/2.5.3""#),
+            line(r#","client_country":"C\u00f4te d'Ivoire""#),
+            line(r#","response_status":"OK""#),
+            line(r#","response_status":99999"#),
+            line(r#","response_bytes":-0"#),
+            line(r#","tags":["a","b"]"#),
+            line(r#","client_ip":null"#),
+            line(r#","http2":fals"#),
+            line(r#","request_bytes":01"#),
+            line(r#""#) + "x",
+            r#"{"request_path":"/versions"}"#.to_string(),
+            "{}".to_string(),
+            String::new(),
+        ];
+        for line in &unusual {
+            assert!(scan(line).is_none(), "{line}");
+        }
+        assert!(serde(&unusual[1]).is_some());
+    }
+}
@@ -0,0 +1,233 @@
+//! Keeping stats in a SQLite database, with one row per time bucket, field, value, and
+//! source file, along with its sketch so uniques can be merged when querying.
+
+use std::path::Path;
+
+use rusqlite::types::Type;
+use rusqlite::{Connection, Error, Result, params};
+
+use crate::bucket::Bucket;
+use crate::hll::HyperLogLog;
+use crate::{Stats, TimeMap, UniqueIndex, ValueUniqueCounter};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS stats (
+        date TEXT NOT NULL,
+        bucket TEXT NOT NULL,
+        field TEXT NOT NULL,
+        value TEXT NOT NULL,
+        source TEXT NOT NULL,
+        total INTEGER NOT NULL,
+        uniques INTEGER NOT NULL,
+        sketch TEXT NOT NULL,
+        PRIMARY KEY (date, field, value, source)
+    );
+    CREATE INDEX IF NOT EXISTS stats_source ON stats (source);
+";
+
+pub struct Database {
+    connection: Connection,
+}
+
+/// Which stats to read back, and how to group them.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    /// Fields and pivots to read, or all of them if empty
+    pub fields: Vec<String>,
+    /// The first date to read, inclusive
+    pub from: Option<String>,
+    /// The last date to read, inclusive
+    pub to: Option<String>,
+    /// Combine the stored buckets into longer ones that each fits inside, like days into
+    /// months
+    pub bucket: Option<Bucket>,
+}
+
+impl Database {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Database> {
+        Database::new(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Database> {
+        Database::new(Connection::open_in_memory()?)
+    }
+
+    fn new(connection: Connection) -> Result<Database> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Database { connection })
+    }
+
+    /// Replaces everything stored for `source` with `stats`.
+    pub fn write(&mut self, source: &str, stats: &Stats) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute("DELETE FROM stats WHERE source = ?1", [source])?;
+        {
+            let mut insert = transaction.prepare(
+                "INSERT INTO stats (date, bucket, field, value, source, total, uniques, sketch)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            for (date, counters) in &stats.times {
+                let bucket = stats.bucket.or_else(|| Bucket::of_key(date));
+                let bucket = bucket.ok_or_else(|| {
+                    Error::ToSqlConversionFailure(format!("{:?} isn't a bucket key", date).into())
+                })?;
+                for (field, values) in counters.histograms() {
+                    for (value, counter) in values.entries() {
+                        let sketch = serde_json::to_string(&counter.sketch())
+                            .expect("sketches always serialize");
+                        insert.execute(params![
+                            date,
+                            bucket.to_string(),
+                            field,
+                            value,
+                            source,
+                            counter.total,
+                            counter.unique(),
+                            sketch,
+                        ])?;
+                    }
+                }
+            }
+        }
+        transaction.commit()
+    }
+
+    /// The files that have stats stored, in order.
+    pub fn sources(&self) -> Result<Vec<String>> {
+        let mut select = self
+            .connection
+            .prepare("SELECT DISTINCT source FROM stats ORDER BY source")?;
+        let sources = select.query_map([], |row| row.get(0))?;
+        sources.collect()
+    }
+
+    /// Adds up the stats of every source, merging uniques by their sketches.
+    pub fn query(&self, query: &Query) -> Result<TimeMap> {
+        let mut sql = "SELECT date, field, value, total, sketch, bucket FROM stats \
+                       WHERE date >= ?1 AND date <= ?2"
+            .to_string();
+        if !query.fields.is_empty() {
+            // The fields are bound as parameters after the dates
+            let placeholders: Vec<String> = (0..query.fields.len())
+                .map(|i| format!("?{}", i + 3))
+                .collect();
+            sql += &format!(" AND field IN ({})", placeholders.join(", "));
+        }
+        // Dates sort as strings, and anything starting with `to`, like the hour
+        // `2018-04-16 04:00` or the day `2018-04-16` in the month `2018-04`, comes before
+        // `to` followed by the last character there is
+        let from = query.from.clone().unwrap_or_default();
+        let to = format!("{}\u{10ffff}", query.to.as_deref().unwrap_or("\u{10ffff}"));
+        let mut parameters = vec![from, to];
+        parameters.extend(query.fields.iter().cloned());
+
+        let mut select = self.connection.prepare(&sql)?;
+        let mut rows = select.query(rusqlite::params_from_iter(parameters))?;
+        let mut times = TimeMap::new();
+        while let Some(row) = rows.next()? {
+            let date: String = row.get(0)?;
+            let field: String = row.get(1)?;
+            let value: String = row.get(2)?;
+            let sketch: String = row.get(4)?;
+            let sketch: HyperLogLog = serde_json::from_str(&sketch)
+                .map_err(|e| Error::FromSqlConversionFailure(4, Type::Text, Box::new(e)))?;
+            let counter = ValueUniqueCounter {
+                total: row.get(3)?,
+                index: UniqueIndex::Approximate(sketch),
+                traffic: None,
+            };
+            let key = match query.bucket {
+                Some(bucket) => {
+                    let from: String = row.get(5)?;
+                    let from: Bucket = from.parse().map_err(|e: String| {
+                        Error::FromSqlConversionFailure(5, Type::Text, e.into())
+                    })?;
+                    bucket
+                        .rekey(from, &date)
+                        .map_err(|e| Error::FromSqlConversionFailure(0, Type::Text, e.into()))?
+                }
+                None => date,
+            };
+            times.entry(key).or_default().add(&field, &value, &counter);
+        }
+        Ok(times)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FieldName, Options, file_stats};
+
+    #[test]
+    fn test_rewriting_a_source_replaces_it() {
+        let opts = Options::default();
+        let stats = file_stats("test/sample_500.log", &opts).unwrap();
+        let mut db = Database::open_in_memory().unwrap();
+
+        db.write("sample_500.log", &stats).unwrap();
+        db.write("sample_500.log", &stats).unwrap();
+        let once = db.query(&Query::default()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&once).unwrap(),
+            serde_json::to_value(&stats.times).unwrap()
+        );
+
+        // The same users in another file add to totals, but not to uniques
+        db.write("copy.log", &stats).unwrap();
+        assert_eq!(db.sources().unwrap(), ["copy.log", "sample_500.log"]);
+        let twice = db.query(&Query::default()).unwrap();
+        for (date, counters) in &stats.times {
+            for (&value, counter) in &counters[FieldName::ruby] {
+                let value = counters.symbols.resolve(value);
+                let doubled = twice[date].get(FieldName::ruby, value).unwrap();
+                assert_eq!(doubled.total, counter.total * 2);
+                assert_eq!(doubled.unique(), counter.unique());
+            }
+        }
+
+        let query = Query {
+            fields: vec!["bundler".to_string()],
+            from: Some("2018-03-10".to_string()),
+            to: Some("2018-03".to_string()),
+            bucket: Some(Bucket::Month),
+        };
+        let march = db.query(&query).unwrap();
+        assert_eq!(march.keys().collect::<Vec<_>>(), ["2018-03"]);
+        let bundler = &march["2018-03"][FieldName::bundler];
+        assert!(march["2018-03"][FieldName::ruby].is_empty());
+        let expected: usize = stats
+            .times
+            .range("2018-03-10".to_string()..="2018-03-31".to_string())
+            .map(|(_, c)| {
+                c[FieldName::bundler]
+                    .values()
+                    .map(|c| c.total)
+                    .sum::<usize>()
+            })
+            .sum();
+        assert_eq!(
+            bundler.values().map(|c| c.total).sum::<usize>(),
+            expected * 2
+        );
+        assert!(expected > 0);
+
+        // Weeks can run from one month into the next, so they can't be queried as months
+        let weekly = Options {
+            bucket: Bucket::Week,
+            ..Default::default()
+        };
+        db.write(
+            "weekly.log",
+            &file_stats("test/sample_500.log", &weekly).unwrap(),
+        )
+        .unwrap();
+        let error = db.query(&query).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("can't turn week buckets into month buckets"),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Interned strings for the values that histograms count, so each value is stored once
+//! per time bucket instead of once per hit.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// An interned string. Symbols are only meaningful with the `Symbols` table they came
+/// from, and compare by id, which is the order values were first seen in, so anything
+/// that needs string order has to sort by the resolved strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Symbol(u32);
+
+/// A table of interned strings.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    ids: HashMap<Arc<str>, Symbol>,
+    strings: Vec<Arc<str>>,
+}
+
+impl Symbols {
+    pub fn intern(&mut self, s: &str) -> Symbol {
+        if let Some(&symbol) = self.ids.get(s) {
+            return symbol;
+        }
+        let symbol = Symbol(u32::try_from(self.strings.len()).expect("too many symbols"));
+        let string: Arc<str> = Arc::from(s);
+        self.strings.push(string.clone());
+        self.ids.insert(string, symbol);
+        symbol
+    }
+
+    /// The symbol for a string, if it has been interned.
+    pub fn get(&self, s: &str) -> Option<Symbol> {
+        self.ids.get(s).copied()
+    }
+
+    pub fn resolve(&self, symbol: Symbol) -> &str {
+        &self.strings[symbol.0 as usize]
+    }
+}
+
+impl fmt::Debug for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(&self.strings).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let mut symbols = Symbols::default();
+        let ruby = symbols.intern("ruby/3.3.0");
+        assert_eq!(ruby, symbols.intern("ruby/3.3.0"));
+        assert_ne!(ruby, symbols.intern("ruby/3.3.1"));
+        assert_eq!(symbols.resolve(ruby), "ruby/3.3.0");
+        assert_eq!(symbols.get("ruby/3.3.1"), Some(Symbol(1)));
+        assert_eq!(symbols.get("ruby/3.4.0"), None);
+        assert_eq!(format!("{symbols:?}"), r#"["ruby/3.3.0", "ruby/3.3.1"]"#);
+
+        // Tables are independent, so the same string can have different ids
+        let mut other = Symbols::default();
+        other.intern("ruby/3.4.0");
+        assert_ne!(other.intern("ruby/3.3.0"), ruby);
+    }
+}
@@ -0,0 +1,154 @@
+//! Bytes served and response times, summed up per counter. Response times go into
+//! histograms with buckets up to a quarter as wide as their values, which merge by adding.
+
+use std::collections::BTreeMap;
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+/// What was served for one request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Response {
+    pub bytes: u64,
+    pub elapsed_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Traffic {
+    pub bytes: u64,
+    pub latency: Latency,
+}
+
+impl Traffic {
+    pub fn record(&mut self, response: &Response) {
+        self.bytes += response.bytes;
+        if let Some(elapsed) = response.elapsed_ms {
+            self.latency.record(elapsed);
+        }
+    }
+
+    pub fn combine(&mut self, other: &Traffic) {
+        self.bytes += other.bytes;
+        self.latency.combine(&other.latency);
+    }
+}
+
+/// A histogram of response times in milliseconds, keyed by the start of each bucket.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Latency(BTreeMap<u64, u64>);
+
+impl Latency {
+    pub fn record(&mut self, elapsed_ms: u64) {
+        *self.0.entry(bucket_start(elapsed_ms)).or_default() += 1;
+    }
+
+    pub fn combine(&mut self, other: &Latency) {
+        for (&bucket, &count) in &other.0 {
+            *self.0.entry(bucket).or_default() += count;
+        }
+    }
+
+    /// The bucket that the `q` quantile (between 0 and 1) falls in, or `None` if nothing
+    /// has been recorded.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let count: u64 = self.0.values().sum();
+        let rank = ((q * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (&bucket, &n) in &self.0 {
+            seen += n;
+            if seen >= rank {
+                return Some(bucket);
+            }
+        }
+        None
+    }
+}
+
+fn bucket_start(value: u64) -> u64 {
+    let bits = u64::BITS - value.leading_zeros();
+    if bits <= 3 {
+        value
+    } else {
+        let shift = bits - 3;
+        (value >> shift) << shift
+    }
+}
+
+impl Serialize for Latency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Latency", 4)?;
+        s.serialize_field("p50", &self.quantile(0.50))?;
+        s.serialize_field("p95", &self.quantile(0.95))?;
+        s.serialize_field("p99", &self.quantile(0.99))?;
+        s.serialize_field("histogram", &self.0)?;
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Latency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // The percentiles are worked out again from the histogram
+        #[derive(Deserialize)]
+        struct Stored {
+            histogram: BTreeMap<u64, u64>,
+        }
+        Ok(Latency(Stored::deserialize(deserializer)?.histogram))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_start() {
+        let starts: Vec<u64> = (0..=20).map(bucket_start).collect();
+        assert_eq!(
+            starts,
+            [
+                0, 1, 2, 3, 4, 5, 6, 7, 8, 8, 10, 10, 12, 12, 14, 14, 16, 16, 16, 16, 20
+            ]
+        );
+        assert_eq!(bucket_start(1000), 896);
+        assert_eq!(bucket_start(u64::MAX), 7 << 61);
+    }
+
+    #[test]
+    fn test_latency_quantiles() {
+        let mut latency = Latency::default();
+        assert_eq!(latency.quantile(0.5), None);
+
+        for ms in 1..=100 {
+            latency.record(ms);
+        }
+        assert_eq!(latency.quantile(0.50), Some(48));
+        assert_eq!(latency.quantile(0.95), Some(80));
+        assert_eq!(latency.quantile(0.99), Some(96));
+
+        let mut traffic = Traffic::default();
+        traffic.record(&Response {
+            bytes: 1375,
+            elapsed_ms: Some(0),
+        });
+        let mut other = Traffic::default();
+        other.record(&Response {
+            bytes: 1000,
+            elapsed_ms: None,
+        });
+        other.record(&Response {
+            bytes: 10,
+            elapsed_ms: Some(66),
+        });
+        traffic.combine(&other);
+        assert_eq!(traffic.bytes, 2385);
+        assert_eq!(
+            serde_json::to_string(&traffic.latency).unwrap(),
+            r#"{"p50":0,"p95":64,"p99":64,"histogram":{"0":1,"64":1}}"#
+        );
+        let json = serde_json::to_string(&traffic.latency).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Latency>(&json).unwrap(),
+            traffic.latency
+        );
+    }
+}
@@ -19,6 +19,9 @@ pub struct UserAgent<'a> {
     pub jruby: Option<&'a str>,
     pub truffleruby: Option<&'a str>,
     pub ci: Option<&'a str>,
+    /// The random identifier Bundler sends with every request in a command
+    #[serde(skip)]
+    pub uid: Option<&'a str>,
     pub gemstash: Option<&'a str>,
 }
 
@@ -50,12 +53,12 @@ impl ParseCtx {
     /*
     lazy_static! {
       // Here is the named regex. The regex created below does not include names, because that interface has borrowing issues 😬
-      // \Abundler/(?<bundler>[0-9a-zA-Z.\-]+) rubygems/(?<rubygems>[0-9a-zA-Z.\-]+) ruby/(?<ruby>[0-9a-zA-Z.\-]+) \((?<platform>.*)\) command/(.*?)(?: jruby/(?<jruby>[0-9a-zA-Z.\-]+))?(?: truffleruby/(?<truffleruby>[0-9a-zA-Z.\-]+))?(?: options/(?<options>.*?))?(?: ci/(?<ci>.*?))? ([a-f0-9]{16})(?: Gemstash/(?<gemstash>[0-9a-zA-Z.\-]+))?\z
+      // \Abundler/(?<bundler>[0-9a-zA-Z.\-]+) rubygems/(?<rubygems>[0-9a-zA-Z.\-]+) ruby/(?<ruby>[0-9a-zA-Z.\-]+) \((?<platform>.*)\) command/(.*?)(?: jruby/(?<jruby>[0-9a-zA-Z.\-]+))?(?: truffleruby/(?<truffleruby>[0-9a-zA-Z.\-]+))?(?: options/(?<options>.*?))?(?: ci/(?<ci>.*?))? (?<uid>[a-f0-9]{16})(?: Gemstash/(?<gemstash>[0-9a-zA-Z.\-]+))?\z
     }
     */
     pub fn new() -> Self {
         Self {
-            bundler_pattern: Regex::new(r"\Abundler/([0-9a-zA-Z.\-]+) rubygems/([0-9a-zA-Z.\-]+) ruby/([0-9a-zA-Z.\-]+) \(([^)]*)\) command/(.*?)(?: jruby/([0-9a-zA-Z.\-]+))?(?: truffleruby/([0-9a-zA-Z.\-]+))?(?: options/(.*?))?(?: ci/(.*?))? [a-f0-9]{16}(?: Gemstash/([0-9a-zA-Z.\-]+))?\z").unwrap(),
+            bundler_pattern: Regex::new(r"\Abundler/([0-9a-zA-Z.\-]+) rubygems/([0-9a-zA-Z.\-]+) ruby/([0-9a-zA-Z.\-]+) \(([^)]*)\) command/(.*?)(?: jruby/([0-9a-zA-Z.\-]+))?(?: truffleruby/([0-9a-zA-Z.\-]+))?(?: options/(.*?))?(?: ci/(.*?))? ([a-f0-9]{16})(?: Gemstash/([0-9a-zA-Z.\-]+))?\z").unwrap(),
             ruby_pattern: Regex::new(r"\A(?:Ruby, )?RubyGems/([0-9a-z.\-]+) (.*) Ruby/([0-9a-z.\-]+) \(.*?\)(?: jruby| truffleruby| rbx)?(?: Gemstash/([0-9a-z.\-]+))?\z").unwrap(),
             // From the "gems" gem
             gem_pattern: Regex::new(r"\ARuby, Gems ([0-9a-z.\-]+)\z").unwrap(),
@@ -124,13 +127,17 @@ impl ParseCtx {
                     Some(loc) => Some(&a[loc.0..loc.1]),
                     _ => None,
                 },
+                uid: match bl.get(10) {
+                    Some(loc) => Some(&a[loc.0..loc.1]),
+                    _ => None,
+                },
                 gemstash: match bl.get(11) {
                     Some(loc) => Some(&a[loc.0..loc.1]),
                     _ => None,
                 },
             })
         } else if self.ruby_pattern.captures_read(rl, a).is_some() {
-            return Some(UserAgent {
+            Some(UserAgent {
                 agent_name: Some("rubygems"),
                 agent_version: match rl.get(1) {
                     Some(loc) => Some(&a[loc.0..loc.1]),
@@ -154,13 +161,14 @@ impl ParseCtx {
                 truffleruby: None,
                 options: None,
                 ci: None,
+                uid: None,
                 gemstash: match rl.get(4) {
                     Some(loc) => Some(&a[loc.0..loc.1]),
                     _ => None,
                 },
-            });
+            })
         } else if self.gem_pattern.captures_read(gl, a).is_some() {
-            return Some(UserAgent {
+            Some(UserAgent {
                 agent_name: Some("gems"),
                 agent_version: match gl.get(1) {
                     Some(loc) => Some(&a[loc.0..loc.1]),
@@ -175,14 +183,15 @@ impl ParseCtx {
                 truffleruby: None,
                 options: None,
                 ci: None,
+                uid: None,
                 gemstash: None,
-            });
+            })
         } else if self
             .generic_pattern
             .captures_read(&mut capture_locations.generic_captures, a)
             .is_some()
         {
-            return Some(UserAgent {
+            Some(UserAgent {
                 agent_name: capture_locations
                     .generic_captures
                     .get(1)
@@ -192,22 +201,28 @@ impl ParseCtx {
                     .get(2)
                     .map(|m| &a[m.0..m.1]),
                 ..Default::default()
-            });
+            })
         } else {
-            return None;
+            None
         }
     }
 }
 
+lazy_static! {
+    static ref USER_AGENT_PARSER: ParseCtx = ParseCtx::new();
+}
+
+/// Parses a user agent with a shared parser, for callers that don't keep their own.
+pub fn parse_user_agent(ua: &str) -> Option<UserAgent<'_>> {
+    let mut capture_locations = USER_AGENT_PARSER.capture_locations();
+    USER_AGENT_PARSER.parse(&mut capture_locations, ua)
+}
+
 pub fn serialize_user_agent<S>(ua: &str, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
-    lazy_static! {
-        static ref USER_AGENT_PARSER: ParseCtx = ParseCtx::new();
-    };
-    let mut capture_locations = USER_AGENT_PARSER.capture_locations();
-    let user_agent = USER_AGENT_PARSER.parse(&mut capture_locations, ua);
+    let user_agent = parse_user_agent(ua);
     serde::Serialize::serialize(&user_agent, serializer)
 }
 
@@ -262,10 +277,33 @@ mod tests {
                 jruby: None,
                 truffleruby: None,
                 ci: None,
+                uid: Some("95ac718b0e500f41"),
                 gemstash: None,
             })
         );
 
+        assert_eq!(
+            ctx.parse(
+                &mut capture_locations,
+                "bundler/1.15.4 rubygems/2.6.14 ruby/2.4.2 (x86_64-w64-mingw32) command/install options/ 6e8fa23dbf26d4ff Gemstash/1.1.0"
+            ),
+            Some(UserAgent {
+                agent_name: Some("bundler"),
+                agent_version: Some("1.15.4"),
+                bundler: Some("1.15.4"),
+                rubygems: Some("2.6.14"),
+                ruby: Some("2.4.2"),
+                platform: Some("x86_64-w64-mingw32"),
+                command: Some("install"),
+                options: Some(""),
+                jruby: None,
+                truffleruby: None,
+                ci: None,
+                uid: Some("6e8fa23dbf26d4ff"),
+                gemstash: Some("1.1.0"),
+            })
+        );
+
         assert_eq!(
             ctx.parse(
                 &mut capture_locations,
@@ -283,6 +321,7 @@ mod tests {
                 jruby: None,
                 truffleruby: None,
                 ci: None,
+                uid: None,
                 gemstash: None,
             })
         );
@@ -301,17 +340,14 @@ mod tests {
                 jruby: None,
                 truffleruby: None,
                 ci: None,
+                uid: None,
                 gemstash: None,
             })
         );
 
         use crate::Options;
-        let opts = Options {
-            paths: vec![],
-            verbose: false,
-            unknown: false,
-        };
-        let file = file::reader("test/client_user_agents.txt", &opts);
+        let opts = Options::default();
+        let file = file::reader("test/client_user_agents.txt", &opts).unwrap();
         for line in file.lines() {
             let input = &line.unwrap();
             ctx.parse(&mut capture_locations, input)
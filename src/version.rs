@@ -0,0 +1,315 @@
+//! Versions that parse, compare, and print the way RubyGems' `Gem::Version` does.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Segment {
+    /// Digits without leading zeros, so that numbers of any size compare correctly.
+    Number(String),
+    Letters(String),
+}
+
+impl Segment {
+    fn is_zero(&self) -> bool {
+        matches!(self, Segment::Number(n) if n == "0")
+    }
+}
+
+impl Ord for Segment {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Segment::Number(a), Segment::Number(b)) => a.len().cmp(&b.len()).then(a.cmp(b)),
+            (Segment::Letters(a), Segment::Letters(b)) => a.cmp(b),
+            (Segment::Letters(_), Segment::Number(_)) => Ordering::Less,
+            (Segment::Number(_), Segment::Letters(_)) => Ordering::Greater,
+        }
+    }
+}
+
+impl PartialOrd for Segment {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Segment::Number(s) | Segment::Letters(s) => f.write_str(s),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Version {
+    version: String,
+    segments: Vec<Segment>,
+}
+
+impl Version {
+    /// Every number and run of letters, in order: `[3, 4, 0, "preview", 1]`.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// The segments without trailing zeros, both in the release and in the prerelease
+    /// part, which is what versions are compared by.
+    pub fn canonical_segments(&self) -> Vec<&Segment> {
+        let letters = self
+            .segments
+            .iter()
+            .position(|s| matches!(s, Segment::Letters(_)))
+            .unwrap_or(self.segments.len());
+        let (release, prerelease) = self.segments.split_at(letters);
+        trim_zeros(release)
+            .iter()
+            .chain(trim_zeros(prerelease))
+            .collect()
+    }
+
+    pub fn is_prerelease(&self) -> bool {
+        self.version.bytes().any(|b| b.is_ascii_alphabetic())
+    }
+
+    /// The version without its prerelease part: `3.4.0` for `3.4.0.preview1`.
+    pub fn release(&self) -> Version {
+        let segments: Vec<Segment> = self
+            .segments
+            .iter()
+            .take_while(|s| matches!(s, Segment::Number(_)))
+            .cloned()
+            .collect();
+        let version = join(&segments);
+        Version { version, segments }
+    }
+
+    /// The first `n` numbers of the release, padded with zeros: `3` and `3.3` for `3.3.1`,
+    /// `3.4` for `3.4.0.preview1`, and `3.0` for `3`.
+    pub fn release_line(&self, n: usize) -> String {
+        let zero = Segment::Number("0".to_string());
+        let release = self.release();
+        let segments: Vec<Segment> = (0..n)
+            .map(|i| release.segments.get(i).unwrap_or(&zero).clone())
+            .collect();
+        join(&segments)
+    }
+}
+
+fn trim_zeros(segments: &[Segment]) -> &[Segment] {
+    let end = segments
+        .iter()
+        .rposition(|s| !s.is_zero())
+        .map_or(0, |i| i + 1);
+    &segments[..end]
+}
+
+fn join(segments: &[Segment]) -> String {
+    let strings: Vec<String> = segments.iter().map(Segment::to_string).collect();
+    strings.join(".")
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseVersionError(String);
+
+impl fmt::Display for ParseVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "malformed version number string {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseVersionError {}
+
+impl FromStr for Version {
+    type Err = ParseVersionError;
+
+    /// Accepts what `Gem::Version.correct?` does: a number, then any dot-separated
+    /// segments of letters and numbers, then optionally `-` and more segments. A blank
+    /// string is version `0`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseVersionError(s.to_string());
+        let trimmed = s.trim();
+        if trimmed.is_empty() {
+            return Ok(Version {
+                version: "0".to_string(),
+                segments: vec![Segment::Number("0".to_string())],
+            });
+        }
+
+        let (release, prerelease) = match trimmed.split_once('-') {
+            Some((release, prerelease)) => (release, Some(prerelease)),
+            None => (trimmed, None),
+        };
+        let alphanumeric =
+            |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric());
+        let mut parts = release.split('.');
+        let first = parts.next().unwrap_or_default();
+        if first.is_empty()
+            || !first.bytes().all(|b| b.is_ascii_digit())
+            || !parts.all(alphanumeric)
+        {
+            return Err(error());
+        }
+        if let Some(prerelease) = prerelease {
+            let valid = prerelease.split('.').all(|part| {
+                !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            });
+            if !valid {
+                return Err(error());
+            }
+        }
+
+        let version = trimmed.replace('-', ".pre.");
+        let mut segments = Vec::new();
+        let bytes = version.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let start = i;
+            if bytes[i].is_ascii_digit() {
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let digits = version[start..i].trim_start_matches('0');
+                let digits = if digits.is_empty() { "0" } else { digits };
+                segments.push(Segment::Number(digits.to_string()));
+            } else if bytes[i].is_ascii_alphabetic() {
+                while i < bytes.len() && bytes[i].is_ascii_alphabetic() {
+                    i += 1;
+                }
+                segments.push(Segment::Letters(version[start..i].to_string()));
+            } else {
+                i += 1;
+            }
+        }
+        Ok(Version { version, segments })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.version)
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let zero = Segment::Number("0".to_string());
+        let (left, right) = (self.canonical_segments(), other.canonical_segments());
+        for i in 0..left.len().max(right.len()) {
+            let l = left.get(i).copied().unwrap_or(&zero);
+            let r = right.get(i).copied().unwrap_or(&zero);
+            match l.cmp(r) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl Hash for Version {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.canonical_segments().hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(v("1.2.3").to_string(), "1.2.3");
+        assert_eq!(v(" 1.2.3 ").to_string(), "1.2.3");
+        assert_eq!(v("").to_string(), "0");
+        assert_eq!(v("9.2.1.0-SNAPSHOT").to_string(), "9.2.1.0.pre.SNAPSHOT");
+        assert_eq!(
+            v("3.4.0.preview1").segments(),
+            [
+                Segment::Number("3".into()),
+                Segment::Number("4".into()),
+                Segment::Number("0".into()),
+                Segment::Letters("preview".into()),
+                Segment::Number("1".into()),
+            ]
+        );
+        let canonical: Vec<String> = v("1.0.0.a.0.0")
+            .canonical_segments()
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(canonical, ["1", "a"]);
+
+        for invalid in [
+            "junk", "1.0\n2.0", "1..2", "1.2 3.4", ".1", "1-", "1.2.3-.a",
+        ] {
+            assert!(invalid.parse::<Version>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_ordering() {
+        let ordered = [
+            "0.9",
+            "1.0.a",
+            "1.0.b1",
+            "1.0.rc1",
+            "1.0",
+            "1.0.1",
+            "1.1.pre",
+            "1.1",
+            "2.9.0",
+            "2.10.0",
+            "10",
+            "18446744073709551616",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert_eq!(v("1.0"), v("1"));
+        assert_eq!(v("1.0.0"), v("1.00"));
+        assert_eq!(v("1.0-a"), v("1.0.pre.a"));
+        assert!(v("1.0-a") < v("1.0"));
+        assert!(!v("1.2.3").is_prerelease());
+        assert!(v("1.2.3.b").is_prerelease());
+    }
+
+    #[test]
+    fn test_release_lines() {
+        for (version, release, major, minor) in [
+            ("3.3.1", "3.3.1", "3", "3.3"),
+            ("2.4.10", "2.4.10", "2", "2.4"),
+            ("3.4.0.preview1", "3.4.0", "3", "3.4"),
+            ("3.4.0-dev", "3.4.0", "3", "3.4"),
+            ("2.6.0dev", "2.6.0", "2", "2.6"),
+            ("9.2.1.0-SNAPSHOT", "9.2.1.0", "9", "9.2"),
+            ("3", "3", "3", "3.0"),
+            ("3.pre", "3", "3", "3.0"),
+        ] {
+            let version = v(version);
+            assert_eq!(version.release().to_string(), release);
+            assert_eq!(version.release_line(1), major);
+            assert_eq!(version.release_line(2), minor);
+        }
+    }
+}